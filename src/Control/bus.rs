@@ -1,13 +1,28 @@
 use crate::cartridge::Rom;
+use crate::cpu::Mem;
 use crate::ppu::NesPPU;
 use crate::ppu::PPU;
 use crate::controller::Controller;
+use crate::cdl::CodeDataLog;
+use crate::opcodes::OpCode;
+use std::cell::RefCell;
+use std::rc::Rc;
 
 
 const RAM: u16 = 0x0000;
 const RAM_MIRRORS_END: u16 = 0x1FFF;
 const PPU_REGISTERS: u16 = 0x2000;
 
+bitflags! {
+    /// Devices that can hold the CPU's IRQ line low. The line stays asserted
+    /// until every source has been acknowledged.
+    pub struct IrqSource: u8 {
+        const APU_FRAME = 0b0000_0001;
+        const APU_DMC   = 0b0000_0010;
+        const MAPPER    = 0b0000_0100;
+    }
+}
+
 pub struct Bus<'call> {
     cpu_vram: [u8; 2048],
     prg_rom: Vec<u8>,
@@ -15,8 +30,10 @@ pub struct Bus<'call> {
     
 
     cycles: usize,
+    irq_sources: IrqSource,
     gameloop_callback: Box<dyn FnMut(&NesPPU, &mut controller) + 'call>,
     controller1: controller,
+    cdl: Option<Rc<RefCell<CodeDataLog>>>,
 }
 
 impl<'a> Bus<'a> {
@@ -30,20 +47,47 @@ impl<'a> Bus<'a> {
             cpu_vram: [0; 2048],
             prg_rom: rom.prg_rom,
             ppu: ppu,
+            cycles: 0,
+            irq_sources: IrqSource::empty(),
             gameloop_callback: Box::from(gameloop_callback),
-            controller1: controller::new()
+            controller1: controller::new(),
+            cdl: None,
         }
     }
 
-    fn read_prg_rom(&self, mut addr: u16) -> u8 {
-        addr -= 0x8000;
-        if self.prg_rom.len() == 0x4000 && addr >= 0x4000 {
-            addr = addr % 0x4000;
-        }
-        self.prg_rom[addr as usize]
+    fn read_prg_rom(&self, addr: u16) -> u8 {
+        self.prg_rom[self.prg_rom_offset(addr).unwrap()]
+    }
+
+    /// Starts code/data logging of PRG and CHR ROM. The CPU marks code and
+    /// data through this bus; the PPU marks the tiles it fetches.
+    pub fn start_cdl(&mut self) -> Rc<RefCell<CodeDataLog>> {
+        let cdl = Rc::new(RefCell::new(CodeDataLog::new(self.prg_rom.len(), self.ppu.chr_rom.len())));
+        self.cdl = Some(cdl.clone());
+        self.ppu.cdl = Some(cdl.clone());
+        cdl
+    }
+
+    pub fn stop_cdl(&mut self) {
+        self.cdl = None;
+        self.ppu.cdl = None;
     }
 
-    pub fn tick(&mut self, cycles: u8) {
+    pub fn set_irq(&mut self, source: IrqSource, active: bool) {
+        self.irq_sources.set(source, active);
+    }
+}
+
+impl Mem for Bus<'_> {
+    fn poll_nmi_status(&mut self) -> Option<u8> {
+        self.ppu.poll_nmi_interrupt()
+    }
+
+    fn poll_irq_status(&self) -> bool {
+        !self.irq_sources.is_empty()
+    }
+
+    fn tick(&mut self, cycles: u8) {
         self.cycles += cycles as usize;
 
         let nmi_before = self.ppu.nmi_interrupt.is_some();
@@ -54,13 +98,46 @@ impl<'a> Bus<'a> {
             (self.gameloop_callback)(&self.ppu, &mut self.controller1);
         }
     }
-    
-    pub fn poll_nmi_status(&mut self) -> Option<u8> {
-        self.ppu.poll_nmi_interrupt()
+
+    fn ppu_position(&self) -> (u16, usize) {
+        (self.ppu.scanline, self.ppu.cycle())
+    }
+
+    fn ppu_registers(&self) -> (u8, u8) {
+        (self.ppu.ctrl.bits(), self.ppu.status.bits())
+    }
+
+    fn read_opcode(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x8000..=0xFFFF => self.read_prg_rom(addr),
+            _ => self.mem_read(addr),
+        }
+    }
+
+    fn log_fetch(&mut self, addr: u16, opcode: &OpCode) {
+        if let Some(cdl) = &self.cdl {
+            cdl.borrow_mut().fetch(self, addr, opcode);
+        }
+    }
+
+    fn log_interrupt(&mut self) {
+        if let Some(cdl) = &self.cdl {
+            cdl.borrow_mut().interrupt();
+        }
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        if addr < 0x8000 {
+            return None;
+        }
+        let mut addr = addr - 0x8000;
+        if self.prg_rom.len() == 0x4000 && addr >= 0x4000 {
+            //mirror if needed
+            addr = addr % 0x4000;
+        }
+        Some(addr as usize)
     }
-}
 
-impl Mem for Bus<'_> {
     fn mem_read(&mut self, addr: u16) -> u8 {
         match addr {
             RAM..=RAM_MIRRORS_END => {
@@ -71,6 +148,8 @@ impl Mem for Bus<'_> {
                 0
             }
             0x2002 => self.ppu.read_status(),
+            0x2004 => self.ppu.read_oam_data(),
+            0x2007 => self.ppu.read_data(),
             0x4000..=0x4015 => {
                 0
             }
@@ -86,13 +165,54 @@ impl Mem for Bus<'_> {
                 let mirror_down_addr = addr & 0b00100000_00000111;
                 self.mem_read(mirror_down_addr)
             }
-            0x8000..=0xFFFF => self.read_prg_rom(addr),
+            0x8000..=0xFFFF => {
+                // logged here rather than in read_prg_rom, which also serves
+                // opcode reads and can't tell them from data
+                if let Some(cdl) = &self.cdl {
+                    cdl.borrow_mut().read(self, addr);
+                }
+                self.read_prg_rom(addr)
+            }
             _ => {
                 0
             }
         }
     }
 
+    fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            RAM..=RAM_MIRRORS_END => {
+                let mirror_down_addr = addr & 0b00000111_11111111;
+                self.cpu_vram[mirror_down_addr as usize]
+            }
+            0x2002 => self.ppu.peek_status(),
+            0x2004 => self.ppu.read_oam_data(),
+            0x2007 => self.ppu.peek_data(),
+            0x4016 => self.controller1.peek(),
+            0x2008..=PPU_REGISTERS_MIRRORS_END => {
+                let mirror_down_addr = addr & 0b00100000_00000111;
+                self.peek(mirror_down_addr)
+            }
+            0x8000..=0xFFFF => self.read_prg_rom(addr),
+            _ => 0,
+        }
+    }
+
+    fn poke(&mut self, addr: u16, data: u8) -> bool {
+        match addr {
+            RAM..=RAM_MIRRORS_END => {
+                self.cpu_vram[(addr & 0b00000111_11111111) as usize] = data;
+                true
+            }
+            0x8000..=0xFFFF => {
+                let offset = self.prg_rom_offset(addr).unwrap();
+                self.prg_rom[offset] = data;
+                true
+            }
+            _ => false,
+        }
+    }
+
     fn mem_write(&mut self, addr: u16, data: u8) {
         match addr {
             RAM..=RAM_MIRRORS_END => {
@@ -115,6 +235,12 @@ impl Mem for Bus<'_> {
             0x2005 => {
                 self.ppu.write_to_scroll(data);
             }
+            0x2006 => {
+                self.ppu.write_to_ppu_addr(data);
+            }
+            0x2007 => {
+                self.ppu.write_to_data(data);
+            }
             0x4014 => {
                 let mut buffer: [u8; 256] = [0; 256];
                 let hi: u16 = (data as u16) << 8;
@@ -133,3 +259,50 @@ impl Mem for Bus<'_> {
     }
 }
 
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::test_rom;
+
+    #[test]
+    fn test_peek_oam_data() {
+        let mut bus = Bus::new(test_rom(), |_, _| {});
+        bus.mem_write(0x2003, 0x10);
+        bus.mem_write(0x2004, 0x66);
+        bus.mem_write(0x2003, 0x10);
+
+        assert_eq!(bus.peek(0x2004), 0x66);
+        assert_eq!(bus.peek(0x200c), 0x66);
+        // $2004 reads don't advance OAMADDR either
+        assert_eq!(bus.mem_read(0x2004), 0x66);
+        assert_eq!(bus.peek(0x2004), 0x66);
+    }
+
+    #[test]
+    fn test_peek_palette_mirrors() {
+        let mut bus = Bus::new(test_rom(), |_, _| {});
+        bus.ppu.palette_table[0x00] = 0x0f;
+        bus.ppu.palette_table[0x04] = 0x21;
+
+        bus.ppu.write_to_ppu_addr(0x3f);
+        bus.ppu.write_to_ppu_addr(0x24);
+        assert_eq!(bus.peek(0x2007), 0x21);
+        bus.ppu.write_to_ppu_addr(0x3f);
+        bus.ppu.write_to_ppu_addr(0xf0);
+        assert_eq!(bus.peek(0x2007), 0x0f);
+    }
+
+    #[test]
+    fn test_poke_rom() {
+        let mut bus = Bus::new(test_rom(), |_, _| {});
+        bus.mem_write(0x8000, 0xea);
+        assert_eq!(bus.peek(0x8000), 0x01);
+
+        assert!(bus.poke(0x8000, 0xea));
+        assert_eq!(bus.peek(0x8000), 0xea);
+        assert!(bus.poke(0x0801, 0x42));
+        assert_eq!(bus.peek(0x0001), 0x42);
+        assert!(!bus.poke(0x2000, 0x80));
+    }
+}
@@ -41,6 +41,14 @@ impl controller {
         }
     }
 
+    /// The bit the next read of $4016 returns, without shifting.
+    pub fn peek(&self) -> u8 {
+        if self.button_index > 7 {
+            return 1;
+        }
+        (self.button_status.bits & (1 << self.button_index)) >> self.button_index
+    }
+
     pub fn set_button_pressed_status(&mut self, button: controllerButton, pressed: bool) {
         self.button_status.set(button, pressed);
     }
@@ -0,0 +1,381 @@
+use crate::cpu::AddressingMode;
+use crate::opcodes::{OpCode, CPU_OPS_CODES};
+use std::collections::HashMap;
+use std::fmt;
+
+/// A line that couldn't be assembled.
+#[derive(Debug, PartialEq)]
+pub struct AsmError {
+    /// 1-based line number in the source.
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Value {
+    Number(u16),
+    Label(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Index {
+    None,
+    X,
+    Y,
+}
+
+#[derive(Debug, Clone)]
+enum Operand {
+    Implied,
+    Accumulator,
+    Immediate(Value),
+    /// `zero_page` is true when the operand was written as one byte.
+    Address { value: Value, index: Index, zero_page: bool },
+    Indirect(Value),
+    IndirectX(Value),
+    IndirectY(Value),
+}
+
+enum Statement {
+    Instruction { op: &'static OpCode, operand: Operand },
+    Bytes(Vec<Value>),
+}
+
+struct Line {
+    number: usize,
+    addr: u16,
+    statement: Statement,
+}
+
+fn error<T>(line: usize, message: String) -> Result<T, AsmError> {
+    Err(AsmError { line: line, message: message })
+}
+
+fn parse_number(text: &str) -> Option<(u16, bool)> {
+    let (digits, radix) = if let Some(hex) = text.strip_prefix('$') {
+        (hex, 16)
+    } else if let Some(bin) = text.strip_prefix('%') {
+        (bin, 2)
+    } else {
+        (text, 10)
+    };
+    let value = u16::from_str_radix(digits, radix).ok()?;
+    let one_byte = match radix {
+        16 => digits.len() <= 2,
+        2 => digits.len() <= 8,
+        _ => value <= 0xff,
+    };
+    Some((value, one_byte))
+}
+
+fn parse_value(text: &str, line: usize) -> Result<(Value, bool), AsmError> {
+    let text = text.trim();
+    if let Some((value, one_byte)) = parse_number(text) {
+        return Ok((Value::Number(value), one_byte));
+    }
+    let is_label = text.chars().next().map_or(false, |c| c.is_ascii_alphabetic() || c == '_')
+        && text.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if is_label {
+        Ok((Value::Label(text.to_string()), false))
+    } else {
+        error(line, format!("bad operand '{}'", text))
+    }
+}
+
+fn parse_operand(text: &str, line: usize) -> Result<Operand, AsmError> {
+    let text: String = text.chars().filter(|c| !c.is_whitespace()).collect();
+    let upper = text.to_ascii_uppercase();
+
+    if text.is_empty() {
+        return Ok(Operand::Implied);
+    }
+    if upper == "A" {
+        return Ok(Operand::Accumulator);
+    }
+    if let Some(rest) = text.strip_prefix('#') {
+        return Ok(Operand::Immediate(parse_value(rest, line)?.0));
+    }
+    if text.starts_with('(') {
+        if upper.ends_with(",X)") {
+            return Ok(Operand::IndirectX(parse_value(&text[1..text.len() - 3], line)?.0));
+        }
+        if upper.ends_with("),Y") {
+            return Ok(Operand::IndirectY(parse_value(&text[1..text.len() - 3], line)?.0));
+        }
+        if text.ends_with(')') {
+            return Ok(Operand::Indirect(parse_value(&text[1..text.len() - 1], line)?.0));
+        }
+        return error(line, format!("bad operand '{}'", text));
+    }
+
+    let (base, index) = if upper.ends_with(",X") {
+        (&text[..text.len() - 2], Index::X)
+    } else if upper.ends_with(",Y") {
+        (&text[..text.len() - 2], Index::Y)
+    } else {
+        (&text[..], Index::None)
+    };
+    let (value, zero_page) = parse_value(base, line)?;
+    Ok(Operand::Address { value: value, index: index, zero_page: zero_page })
+}
+
+/// Picks the encoding of `mnemonic` that fits `operand`. Unofficial opcodes
+/// are only used when spelled with their `*`, as in `*NOP $10` or `*LAX $20`;
+/// plain `NOP $10` or `LAX $20` is an error.
+fn find_opcode(mnemonic: &str, operand: &Operand) -> Option<&'static OpCode> {
+    let candidates: Vec<&'static OpCode> = CPU_OPS_CODES
+        .iter()
+        .filter(|op| op.mnemonic == mnemonic)
+        .collect();
+    let with_mode = |wanted: AddressingMode| {
+        candidates
+            .iter()
+            .find(|op| std::mem::discriminant(&op.mode) == std::mem::discriminant(&wanted))
+            .cloned()
+    };
+
+    match operand {
+        Operand::Implied | Operand::Accumulator => candidates
+            .iter()
+            .find(|op| op.len == 1)
+            .cloned(),
+        Operand::Immediate(_) => with_mode(AddressingMode::Immediate),
+        Operand::IndirectX(_) => with_mode(AddressingMode::Indirect_X),
+        Operand::IndirectY(_) => with_mode(AddressingMode::Indirect_Y),
+        Operand::Indirect(_) => candidates.iter().find(|op| op.code == 0x6c).cloned(),
+        Operand::Address { index, zero_page, .. } => {
+            if let Some(branch) = candidates.iter().find(|op| op.is_branch()) {
+                return if *index == Index::None { Some(*branch) } else { None };
+            }
+            let (zp_mode, abs_mode) = match index {
+                Index::None => (AddressingMode::ZeroPage, AddressingMode::Absolute),
+                Index::X => (AddressingMode::ZeroPage_X, AddressingMode::Absolute_X),
+                Index::Y => (AddressingMode::ZeroPage_Y, AddressingMode::Absolute_Y),
+            };
+            let zp = if *zero_page { with_mode(zp_mode) } else { None };
+            zp.or_else(|| with_mode(abs_mode)).or_else(|| {
+                // JMP and JSR take a plain 16-bit address
+                candidates
+                    .iter()
+                    .find(|op| *index == Index::None && op.len == 3 && op.code != 0x6c)
+                    .cloned()
+            })
+        }
+    }
+}
+
+fn strip_comment(text: &str) -> &str {
+    match text.find(';') {
+        Some(idx) => &text[..idx],
+        None => text,
+    }
+}
+
+fn parse(source: &str, origin: u16) -> Result<(Vec<Line>, HashMap<String, u16>), AsmError> {
+    let mut lines = vec![];
+    let mut labels = HashMap::new();
+    let mut addr = origin;
+
+    for (idx, raw) in source.lines().enumerate() {
+        let number = idx + 1;
+        let mut text = strip_comment(raw).trim();
+
+        if let Some(colon) = text.find(':') {
+            let label = text[..colon].trim();
+            if label.is_empty() || !label.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                return error(number, format!("bad label '{}'", label));
+            }
+            if labels.insert(label.to_string(), addr).is_some() {
+                return error(number, format!("label '{}' defined twice", label));
+            }
+            text = text[colon + 1..].trim();
+        }
+        if text.is_empty() {
+            continue;
+        }
+
+        let (mnemonic, rest) = match text.find(char::is_whitespace) {
+            Some(idx) => (&text[..idx], text[idx..].trim()),
+            None => (text, ""),
+        };
+        let mnemonic = mnemonic.to_ascii_uppercase();
+
+        let statement = if mnemonic == ".DB" || mnemonic == ".BYTE" {
+            let mut values = vec![];
+            for item in rest.split(',') {
+                values.push(parse_value(item, number)?.0);
+            }
+            Statement::Bytes(values)
+        } else {
+            let operand = parse_operand(rest, number)?;
+            match find_opcode(&mnemonic, &operand) {
+                Some(op) => Statement::Instruction { op: op, operand: operand },
+                None => return error(number, format!("no encoding for '{}'", text)),
+            }
+        };
+
+        let len = match &statement {
+            Statement::Instruction { op, .. } => op.len as u16,
+            Statement::Bytes(values) => values.len() as u16,
+        };
+        lines.push(Line { number: number, addr: addr, statement: statement });
+        addr = addr.wrapping_add(len);
+    }
+
+    Ok((lines, labels))
+}
+
+fn resolve(value: &Value, labels: &HashMap<String, u16>, line: usize) -> Result<u16, AsmError> {
+    match value {
+        Value::Number(n) => Ok(*n),
+        Value::Label(name) => match labels.get(name) {
+            Some(addr) => Ok(*addr),
+            None => error(line, format!("unknown label '{}'", name)),
+        },
+    }
+}
+
+fn byte(value: u16, line: usize) -> Result<u8, AsmError> {
+    if value > 0xff {
+        return error(line, format!("${:04X} does not fit in a byte", value));
+    }
+    Ok(value as u8)
+}
+
+/// Assembles `source` as if loaded at `origin`. One instruction per line, with
+/// optional `label:` prefixes, `;` comments and `.db` data. Operands use the
+/// usual syntax: `#$05`, `$10,X`, `$1234,Y`, `($20,X)`, `($20),Y`, `($FFFC)`,
+/// `A`, or a label. Branch labels become relative offsets; any other label is
+/// a 16-bit address.
+pub fn assemble(source: &str, origin: u16) -> Result<Vec<u8>, AsmError> {
+    let (lines, labels) = parse(source, origin)?;
+    let mut program = vec![];
+
+    for line in lines.iter() {
+        let (op, operand) = match &line.statement {
+            Statement::Bytes(values) => {
+                for value in values.iter() {
+                    program.push(byte(resolve(value, &labels, line.number)?, line.number)?);
+                }
+                continue;
+            }
+            Statement::Instruction { op, operand } => (op, operand),
+        };
+        program.push(op.code);
+
+        let value = match operand {
+            Operand::Implied | Operand::Accumulator => continue,
+            Operand::Immediate(v)
+            | Operand::Indirect(v)
+            | Operand::IndirectX(v)
+            | Operand::IndirectY(v)
+            | Operand::Address { value: v, .. } => resolve(v, &labels, line.number)?,
+        };
+
+        if op.is_branch() {
+            let offset = value as i32 - (line.addr as i32 + 2);
+            if offset < -128 || offset > 127 {
+                return error(line.number, format!("branch to ${:04X} out of range", value));
+            }
+            program.push(offset as i8 as u8);
+        } else if op.len == 2 {
+            program.push(byte(value, line.number)?);
+        } else {
+            program.push(value as u8);
+            program.push((value >> 8) as u8);
+        }
+    }
+
+    Ok(program)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::{FlatMemory, Mem};
+    use crate::disasm::disassemble;
+
+    #[test]
+    fn test_assemble_addressing_modes() {
+        let program = assemble(
+            "
+            lda #$05
+            sta $10,X
+            lda ($20),Y
+            ldx $10,Y      ; LDX has a zero page,Y form
+            lda $10,Y      ; LDA doesn't, so this widens to absolute,Y
+            sta ($20,X)
+            asl A
+            jmp ($FFFC)
+            ",
+            0x0600,
+        )
+        .unwrap();
+
+        assert_eq!(
+            program,
+            vec![
+                0xa9, 0x05, 0x95, 0x10, 0xb1, 0x20, 0xb6, 0x10, 0xb9, 0x10, 0x00, 0x81, 0x20,
+                0x0a, 0x6c, 0xfc, 0xff,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_assemble_labels() {
+        let program = assemble(
+            "
+            start: ldx #$03
+            loop:  dex
+                   bne loop
+                   jsr done
+                   jmp start
+            done:  rts
+            table: .db $01, 2, %11
+            ",
+            0xc000,
+        )
+        .unwrap();
+
+        let mut mem = FlatMemory::new();
+        for (i, byte) in program.iter().enumerate() {
+            mem.mem_write(0xc000 + i as u16, *byte);
+        }
+        let text: Vec<String> = disassemble(&mem, 0xc000, 0xc00b).map(|i| i.to_string()).collect();
+        assert_eq!(
+            text,
+            vec![
+                "C000  A2 03     LDX #$03",
+                "C002  CA        DEX",
+                "C003  D0 FD     BNE $C002",
+                "C005  20 0B C0  JSR $C00B",
+                "C008  4C 00 C0  JMP $C000",
+                "C00B  60        RTS",
+            ]
+        );
+        assert_eq!(&program[12..], &[0x01, 0x02, 0x03]);
+    }
+
+    #[test]
+    fn test_assemble_errors() {
+        assert_eq!(assemble("lda #$05\nbeq nowhere", 0).unwrap_err().line, 2);
+        assert!(assemble("lda ($20),X", 0).is_err());
+        assert!(assemble("lda #$1234", 0).is_err());
+        assert!(assemble("x: nop\nx: nop", 0).is_err());
+        assert!(assemble("nop $10", 0).is_err());
+        assert!(assemble("lax $10", 0).is_err());
+        assert!(assemble("dcp $1234,X", 0).is_err());
+    }
+
+    #[test]
+    fn test_assemble_unofficial() {
+        let program = assemble("nop\n*nop\n*nop $10\n*lax ($20),Y\nsbc #$01\n*sbc #$01", 0).unwrap();
+        assert_eq!(program, vec![0xea, 0x1a, 0x04, 0x10, 0xb3, 0x20, 0xe9, 0x01, 0xeb, 0x01]);
+    }
+}
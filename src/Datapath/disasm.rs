@@ -0,0 +1,228 @@
+use crate::cpu::AddressingMode;
+use crate::cpu::Mem;
+use crate::opcodes::{OpCode, OPCODES_MAP};
+use crate::symbols::Symbols;
+use std::fmt;
+
+/// One decoded instruction, or a single data byte when the byte at `addr`
+/// isn't an opcode or the instruction would run past the end of the range.
+pub struct Instruction {
+    pub addr: u16,
+    pub bytes: Vec<u8>,
+    /// None for data bytes.
+    pub opcode: Option<&'static OpCode>,
+    pub operand: String,
+    /// Absolute destination of a branch, JMP or JSR.
+    pub target: Option<u16>,
+    /// Symbol at `addr`, when disassembling with symbols.
+    pub label: Option<String>,
+}
+
+impl Instruction {
+    pub fn mnemonic(&self) -> &'static str {
+        match self.opcode {
+            Some(op) => op.mnemonic,
+            None => ".DB",
+        }
+    }
+
+    pub fn len(&self) -> u16 {
+        self.bytes.len() as u16
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let hex_str = self
+            .bytes
+            .iter()
+            .map(|z| format!("{:02X}", z))
+            .collect::<Vec<String>>()
+            .join(" ");
+        let line = format!("{:04X}  {:8}  {} {}", self.addr, hex_str, self.mnemonic(), self.operand);
+        write!(f, "{}", line.trim_end())
+    }
+}
+
+/// Formats the operand of `op` at `addr`, given its operand bytes. Nothing is
+/// dereferenced, so the result doesn't depend on register or memory state.
+/// `name` gets the first say on how an address is written.
+fn format_operand<F>(op: &OpCode, addr: u16, operand: &[u8], name: F) -> (String, Option<u16>)
+where
+    F: Fn(u16) -> Option<String>,
+{
+    let byte = operand.get(0).cloned().unwrap_or(0);
+    let word = (operand.get(1).cloned().unwrap_or(0) as u16) << 8 | byte as u16;
+    let zp = name(byte as u16).unwrap_or_else(|| format!("${:02X}", byte));
+    let abs = |word: u16| name(word).unwrap_or_else(|| format!("${:04X}", word));
+
+    match op.mode {
+        AddressingMode::Immediate => (format!("#${:02X}", byte), None),
+        AddressingMode::ZeroPage => (zp, None),
+        AddressingMode::ZeroPage_X => (format!("{},X", zp), None),
+        AddressingMode::ZeroPage_Y => (format!("{},Y", zp), None),
+        AddressingMode::Absolute => (abs(word), None),
+        AddressingMode::Absolute_X => (format!("{},X", abs(word)), None),
+        AddressingMode::Absolute_Y => (format!("{},Y", abs(word)), None),
+        AddressingMode::Indirect_X => (format!("({},X)", zp), None),
+        AddressingMode::Indirect_Y => (format!("({}),Y", zp), None),
+        AddressingMode::NoneAddressing => match op.len {
+            1 => match op.code {
+                0x0a | 0x4a | 0x2a | 0x6a => (String::from("A"), None),
+                _ => (String::new(), None),
+            },
+            2 if op.is_branch() => {
+                // relative branches: the offset counts from the next instruction
+                let target = addr.wrapping_add(2).wrapping_add((byte as i8) as u16);
+                (abs(target), Some(target))
+            }
+            3 if op.code == 0x6c => (format!("({})", abs(word)), None),
+            3 => (abs(word), Some(word)),
+            _ => (String::new(), None),
+        },
+    }
+}
+
+/// Decodes the instruction at `addr`, never reading past `end`.
+pub fn decode<M: Mem>(mem: &M, addr: u16, end: u16) -> Instruction {
+    decode_with(mem, addr, end, None)
+}
+
+fn decode_with<M: Mem>(mem: &M, addr: u16, end: u16, symbols: Option<&Symbols>) -> Instruction {
+    let name = |addr: u16| symbols.and_then(|s| s.label(mem, addr)).map(|s| s.to_string());
+    let code = mem.peek(addr);
+    let room = end.wrapping_sub(addr) as u32 + 1;
+
+    match OPCODES_MAP.get(&code) {
+        Some(op) if op.len as u32 <= room => {
+            let bytes: Vec<u8> = (0..op.len as u16).map(|i| mem.peek(addr.wrapping_add(i))).collect();
+            let (operand, target) = format_operand(op, addr, &bytes[1..], name);
+            Instruction {
+                addr: addr,
+                bytes: bytes,
+                opcode: Some(*op),
+                operand: operand,
+                target: target,
+                label: name(addr),
+            }
+        }
+        _ => Instruction {
+            addr: addr,
+            bytes: vec![code],
+            opcode: None,
+            operand: format!("${:02X}", code),
+            target: None,
+            label: name(addr),
+        },
+    }
+}
+
+/// Linear sweep over `start..=end`. Memory is only peeked, so it is safe to
+/// point at a live bus.
+pub struct Disassembly<'a, M: Mem> {
+    mem: &'a M,
+    next: u32,
+    end: u32,
+    symbols: Option<&'a Symbols>,
+}
+
+impl<'a, M: Mem> Disassembly<'a, M> {
+    /// Writes operand addresses as labels and fills in `Instruction::label`.
+    pub fn with_symbols(mut self, symbols: &'a Symbols) -> Self {
+        self.symbols = Some(symbols);
+        self
+    }
+}
+
+impl<'a, M: Mem> Iterator for Disassembly<'a, M> {
+    type Item = Instruction;
+
+    fn next(&mut self) -> Option<Instruction> {
+        if self.next > self.end {
+            return None;
+        }
+        let instruction = decode_with(self.mem, self.next as u16, self.end as u16, self.symbols);
+        self.next += instruction.len() as u32;
+        Some(instruction)
+    }
+}
+
+/// Disassembles `start..=end` without executing anything.
+pub fn disassemble<M: Mem>(mem: &M, start: u16, end: u16) -> Disassembly<'_, M> {
+    Disassembly {
+        mem: mem,
+        next: start as u32,
+        end: end as u32,
+        symbols: None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::FlatMemory;
+
+    #[test]
+    fn test_disassemble_range() {
+        let mut mem = FlatMemory::new();
+        mem.load(
+            0xc000,
+            &[
+                0xa9, 0x05, // LDA #$05
+                0xb1, 0x20, // LDA ($20),Y
+                0xd0, 0xfa, // BNE $C000
+                0x0a, // ASL A
+                0x20, 0x34, 0x12, // JSR $1234
+                0x6c, 0xfc, 0xff, // JMP ($FFFC)
+                0x8b, 0x07, // *XAA #$07
+                0x4c, 0x00, // JMP cut off by the end of the range
+            ],
+        );
+
+        let lines: Vec<Instruction> = disassemble(&mem, 0xc000, 0xc010).collect();
+        let text: Vec<String> = lines.iter().map(|i| i.to_string()).collect();
+        assert_eq!(
+            text,
+            vec![
+                "C000  A9 05     LDA #$05",
+                "C002  B1 20     LDA ($20),Y",
+                "C004  D0 FA     BNE $C000",
+                "C006  0A        ASL A",
+                "C007  20 34 12  JSR $1234",
+                "C00A  6C FC FF  JMP ($FFFC)",
+                "C00D  8B 07     *XAA #$07",
+                "C00F  4C        .DB $4C",
+                "C010  00        BRK",
+            ]
+        );
+        assert_eq!(lines[2].target, Some(0xc000));
+        assert_eq!(lines[4].target, Some(0x1234));
+        assert_eq!(lines[5].target, None);
+    }
+
+    #[test]
+    fn test_disassemble_with_symbols() {
+        let mut mem = FlatMemory::new();
+        // JSR $C3A0; LDA ($10),Y; BNE $C000
+        mem.load(0xc000, &[0x20, 0xa0, 0xc3, 0xb1, 0x10, 0xd0, 0xf9]);
+        let mut symbols = Symbols::new();
+        symbols.load_nl("$C000#Main#\n$C3A0#UpdateSprites#\n$0010#ptr#\n", None);
+
+        let lines: Vec<Instruction> = disassemble(&mem, 0xc000, 0xc006).with_symbols(&symbols).collect();
+        let text: Vec<String> = lines.iter().map(|i| i.to_string()).collect();
+        assert_eq!(
+            text,
+            vec!["C000  20 A0 C3  JSR UpdateSprites", "C003  B1 10     LDA (ptr),Y", "C005  D0 F9     BNE Main"]
+        );
+        assert_eq!(lines[0].label.as_deref(), Some("Main"));
+        assert_eq!(lines[1].label, None);
+    }
+
+    #[test]
+    fn test_disassemble_to_end_of_memory() {
+        let mut mem = FlatMemory::new();
+        mem.load(0xfffe, &[0xea, 0xea]);
+
+        assert_eq!(disassemble(&mem, 0xfff0, 0xffff).count(), 16);
+    }
+}
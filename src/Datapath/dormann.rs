@@ -0,0 +1,110 @@
+use crate::cpu::{CpuVariant, FlatMemory, Mem, CPU};
+use crate::nestest::FIXTURES_DIR;
+
+/// Klaus Dormann's 6502 test suite. The binaries are not checked in; assemble
+/// them with the default settings and drop them next to the nestest fixtures.
+pub const FUNCTIONAL_TEST: &str = "6502_functional_test.bin";
+pub const DECIMAL_TEST: &str = "6502_decimal_test.bin";
+
+/// The functional test loads as a full 64 KiB image and starts at $0400. A
+/// pass ends in the `jmp *` at $3469; any other trap is the failing check.
+pub const FUNCTIONAL_START: u16 = 0x0400;
+pub const FUNCTIONAL_SUCCESS: u16 = 0x3469;
+
+/// The decimal test is assembled at $0200 and clears ERROR once every
+/// operand pair has been checked.
+pub const DECIMAL_START: u16 = 0x0200;
+pub const DECIMAL_ERROR: u16 = 0x000b;
+
+const MAX_INSTRUCTIONS: usize = 100_000_000;
+
+fn load_image(image: &[u8], origin: u16) -> CPU<FlatMemory> {
+    let mut memory = FlatMemory::new();
+    // a full image carries its own zero page and vectors
+    let at = if image.len() == 0x10000 { 0 } else { origin };
+    memory.load(at, image);
+
+    let mut cpu = CPU::with_variant(memory, CpuVariant::Nmos6502);
+    cpu.program_counter = origin;
+    cpu
+}
+
+/// Runs until the program branches or jumps to itself, or until `stop` says
+/// the opcode at PC ends the test. Returns the PC it stopped at, or None if
+/// it was still running after `MAX_INSTRUCTIONS`.
+pub fn run_until_trap<M: Mem, F: Fn(u8) -> bool>(cpu: &mut CPU<M>, stop: F) -> Option<u16> {
+    let mut last_pc = None;
+    let mut count = 0;
+    let mut trapped = None;
+
+    cpu.run_with_callback(|cpu| {
+        let pc = cpu.program_counter;
+        if last_pc == Some(pc) || stop(cpu.peek(pc)) {
+            trapped = Some(pc);
+            cpu.halt();
+        }
+        count += 1;
+        if count > MAX_INSTRUCTIONS {
+            cpu.halt();
+        }
+        last_pc = Some(pc);
+    });
+    trapped
+}
+
+/// Returns the address the functional test trapped at; a pass is
+/// `FUNCTIONAL_SUCCESS`.
+pub fn run_functional(image: &[u8]) -> Option<u16> {
+    let mut cpu = load_image(image, FUNCTIONAL_START);
+    run_until_trap(&mut cpu, |_| false)
+}
+
+/// Returns the ERROR byte once the decimal test finishes, 0 on a pass. The
+/// stock source ends in a 65C02 STP ($DB); a BRK or `jmp *` ending works too.
+pub fn run_decimal(image: &[u8]) -> Option<u8> {
+    let mut cpu = load_image(image, DECIMAL_START);
+    run_until_trap(&mut cpu, |opcode| opcode == 0xdb || opcode == 0x00)?;
+    Some(cpu.peek(DECIMAL_ERROR))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+    use std::path::Path;
+
+    fn fixture(name: &str) -> Option<Vec<u8>> {
+        let path = Path::new(FIXTURES_DIR).join(name);
+        if !path.exists() {
+            eprintln!("skipping {}: not found in {}", name, FIXTURES_DIR);
+            return None;
+        }
+        Some(fs::read(path).unwrap())
+    }
+
+    #[test]
+    fn test_run_until_trap() {
+        let mut memory = FlatMemory::new();
+        // LDX #$03; DEX; BNE -3; JMP $0605
+        memory.load(0x0600, &[0xa2, 0x03, 0xca, 0xd0, 0xfd, 0x4c, 0x05, 0x06]);
+        let mut cpu = CPU::new(memory);
+        cpu.program_counter = 0x0600;
+
+        assert_eq!(run_until_trap(&mut cpu, |_| false), Some(0x0605));
+        assert_eq!(cpu.register_x, 0);
+    }
+
+    #[test]
+    fn test_functional_suite() {
+        if let Some(image) = fixture(FUNCTIONAL_TEST) {
+            assert_eq!(run_functional(&image), Some(FUNCTIONAL_SUCCESS));
+        }
+    }
+
+    #[test]
+    fn test_decimal_suite() {
+        if let Some(image) = fixture(DECIMAL_TEST) {
+            assert_eq!(run_decimal(&image), Some(0));
+        }
+    }
+}
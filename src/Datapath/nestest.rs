@@ -0,0 +1,169 @@
+use crate::bus::Bus;
+use crate::cartridge::Rom;
+use crate::cpu::CPU;
+use crate::trace::trace;
+use std::fmt;
+
+/// nestest.nes and nestest.log are not checked in; drop them here to run the
+/// conformance test.
+pub const FIXTURES_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures");
+
+const CONTEXT_LINES: usize = 5;
+
+/// The first line where our trace and the golden log disagree.
+pub struct Divergence {
+    /// 1-based line number in the golden log.
+    pub line: usize,
+    pub expected: String,
+    pub actual: String,
+    /// Matching lines leading up to the divergence, oldest first.
+    pub context: Vec<String>,
+    /// The golden lines that follow the divergence.
+    pub expected_after: Vec<String>,
+    /// Our lines that follow the divergence.
+    pub actual_after: Vec<String>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "trace diverges from golden log at line {}", self.line)?;
+        for line in self.context.iter() {
+            writeln!(f, "    {}", line)?;
+        }
+        write!(f, "  - {}\n  + {}", self.expected, self.actual)?;
+        for idx in 0..self.expected_after.len().max(self.actual_after.len()) {
+            if let Some(line) = self.expected_after.get(idx) {
+                write!(f, "\n  - {}", line)?;
+            }
+            if let Some(line) = self.actual_after.get(idx) {
+                write!(f, "\n  + {}", line)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Cuts a golden line down to the columns the tracer produces, so the PPU and
+/// CYC columns are only compared once `trace` emits them.
+fn comparable<'a>(golden: &'a str, actual: &str) -> &'a str {
+    for column in [" PPU:", " CYC:"].iter() {
+        if !actual.contains(column) {
+            if let Some(idx) = golden.find(column) {
+                return golden[..idx].trim_end();
+            }
+        }
+    }
+    golden.trim_end()
+}
+
+/// Compares `lines` with the golden log line by line and returns how many
+/// matched. Running out of trace before the log ends counts as a divergence.
+pub fn diff_trace(lines: &[String], golden: &str) -> Result<usize, Divergence> {
+    let mut matched = 0;
+
+    for (idx, expected) in golden.lines().enumerate() {
+        let actual = lines.get(idx).map(|l| l.trim_end()).unwrap_or("<end of trace>");
+        let expected = comparable(expected, actual);
+
+        if expected != actual {
+            let from = idx.saturating_sub(CONTEXT_LINES);
+            return Err(Divergence {
+                line: idx + 1,
+                expected: expected.to_string(),
+                actual: actual.to_string(),
+                context: lines[from..idx].to_vec(),
+                expected_after: golden
+                    .lines()
+                    .skip(idx + 1)
+                    .take(CONTEXT_LINES)
+                    .map(|line| comparable(line, actual).to_string())
+                    .collect(),
+                actual_after: lines
+                    .iter()
+                    .skip(idx + 1)
+                    .take(CONTEXT_LINES)
+                    .map(|line| line.trim_end().to_string())
+                    .collect(),
+            });
+        }
+        matched += 1;
+    }
+
+    Ok(matched)
+}
+
+/// Runs nestest in automation mode (PC = $C000) and collects up to
+/// `max_lines` trace lines.
+pub fn run_nestest(rom: Rom, max_lines: usize) -> Vec<String> {
+    let bus = Bus::new(rom, |_, _| {});
+    let mut cpu = CPU::new(bus);
+    cpu.reset();
+    cpu.program_counter = 0xC000;
+
+    let mut result: Vec<String> = vec![];
+    cpu.run_with_callback(|cpu| {
+        result.push(trace(cpu));
+        if result.len() >= max_lines {
+            cpu.halt();
+        }
+    });
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+    use std::path::Path;
+
+    #[test]
+    fn test_diff_reports_first_divergence() {
+        let golden = "C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7\n\
+                      C5F5  A2 00     LDX #$00                        A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 30 CYC:10\n\
+                      C5F7  86 00     STX $00 = 00                    A:00 X:00 Y:00 P:26 SP:FD PPU:  0, 36 CYC:12\n";
+        let lines = vec![
+            "C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD".to_string(),
+            "C5F5  A2 00     LDX #$00                        A:00 X:00 Y:00 P:24 SP:FD".to_string(),
+            "C5F7  86 00     STX $00 = 00                    A:00 X:00 Y:00 P:24 SP:FD".to_string(),
+        ];
+
+        let divergence = diff_trace(&lines, golden).err().unwrap();
+        assert_eq!(divergence.line, 3);
+        assert_eq!(divergence.context.len(), 2);
+        assert!(divergence.expected.ends_with("P:26 SP:FD"));
+        assert!(divergence.expected_after.is_empty());
+
+        let mut early = lines.clone();
+        early[1] = early[1].replace("LDX #$00", "LDX #$01");
+        let divergence = diff_trace(&early, golden).err().unwrap();
+        assert_eq!(divergence.line, 2);
+        assert_eq!(divergence.context.len(), 1);
+        assert_eq!(
+            divergence.expected_after,
+            vec!["C5F7  86 00     STX $00 = 00                    A:00 X:00 Y:00 P:26 SP:FD"]
+        );
+        assert_eq!(divergence.actual_after, vec![lines[2].clone()]);
+        assert!(divergence.to_string().ends_with(&format!("\n  + {}", lines[2])));
+
+        assert_eq!(diff_trace(&lines[..2], &golden[..golden.find("C5F7").unwrap()]).ok(), Some(2));
+    }
+
+    #[test]
+    fn test_nestest_golden_log() {
+        let rom_path = Path::new(FIXTURES_DIR).join("nestest.nes");
+        let log_path = Path::new(FIXTURES_DIR).join("nestest.log");
+        if !rom_path.exists() || !log_path.exists() {
+            eprintln!("skipping nestest: no fixtures in {}", FIXTURES_DIR);
+            return;
+        }
+
+        let raw = fs::read(rom_path).unwrap();
+        let rom = Rom::new(&raw).unwrap();
+        let golden = fs::read_to_string(log_path).unwrap();
+
+        let lines = run_nestest(rom, golden.lines().count());
+        if let Err(divergence) = diff_trace(&lines, &golden) {
+            panic!("{}", divergence);
+        }
+    }
+}
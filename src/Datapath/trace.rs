@@ -2,22 +2,37 @@ use crate::cpu::AddressingMode;
 use crate::cpu::Mem;
 use crate::cpu::CPU;
 use crate::opcodes;
-use crate::Busgit remote -v;
+use crate::symbols::Symbols;
+use std::collections::HashMap;
 
+/// Formats the instruction at PC as a nestest/Nintendulator log line, with
+/// the registers, PPU position and cycle count before it executes. Memory is
+/// only peeked, so tracing never changes what the program sees.
+pub fn trace<M: Mem>(cpu: &CPU<M>) -> String {
+    format_trace(cpu, None)
+}
 
-pub fn trace(cpu: CPU) -> String {
+/// Same as `trace`, with the operand address replaced by its label when
+/// `symbols` has one, e.g. `JSR UpdateSprites`.
+pub fn trace_with_symbols<M: Mem>(cpu: &CPU<M>, symbols: &Symbols) -> String {
+    format_trace(cpu, Some(symbols))
+}
 
-    let code = cpu.mem_read(cpu.program_counter);
+fn format_trace<M: Mem>(cpu: &CPU<M>, symbols: Option<&Symbols>) -> String {
+    let ref opscodes: HashMap<u8, &'static opcodes::OpCode> = *opcodes::OPCODES_MAP;
+
+    let code = cpu.peek(cpu.program_counter);
     let ops = opscodes.get(&code).unwrap();
 
     let begin = cpu.program_counter;
     let mut hex_dump = vec![];
+    hex_dump.push(code);
 
     let (mem_addr, stored_value) = match ops.mode {
         AddressingMode::Immediate | AddressingMode::NoneAddressing => (0, 0),
         _ => {
-            let addr = cpu.get_absolute_address(&ops.mode,begin +1);
-            (addr, cpu.mem_read(addr))
+            let (addr, _) = cpu.peek_absolute_address(&ops.mode, begin.wrapping_add(1));
+            (addr, cpu.peek(addr))
         }
     };
 
@@ -29,8 +44,7 @@ pub fn trace(cpu: CPU) -> String {
 
 
         2 => {
-            let address: u8 = cpu.mem_read(begin + 1);
-            // let value = cpu.mem_read(address));
+            let address: u8 = cpu.peek(begin.wrapping_add(1));
             hex_dump.push(address);
 
             match ops.mode {
@@ -40,26 +54,53 @@ pub fn trace(cpu: CPU) -> String {
                     "${:02x},X @ {:02x} = {:02x}",
                     address, mem_addr, stored_value
                 ),
+                AddressingMode::ZeroPage_Y => format!(
+                    "${:02x},Y @ {:02x} = {:02x}",
+                    address, mem_addr, stored_value
+                ),
+                AddressingMode::Indirect_X => format!(
+                    "(${:02x},X) @ {:02x} = {:04x} = {:02x}",
+                    address,
+                    (address.wrapping_add(cpu.register_x)),
+                    mem_addr,
+                    stored_value
+                ),
+                AddressingMode::Indirect_Y => format!(
+                    "(${:02x}),Y = {:04x} @ {:04x} = {:02x}",
+                    address,
+                    (mem_addr.wrapping_sub(cpu.register_y as u16)),
+                    mem_addr,
+                    stored_value
+                ),
+                AddressingMode::NoneAddressing => {
+                    // relative branches: the offset counts from the next instruction
+                    let address = begin.wrapping_add(2).wrapping_add((address as i8) as u16);
+                    format!("${:04x}", address)
+                }
+                _ => panic!(
+                    "unexpected addressing mode {:?} has ops-len 2. code {:02x}",
+                    ops.mode, ops.code
+                ),
             }
         }
 
         3 => {
-            let address_lo = cpu.mem_read(begin + 1);
-            let address_hi = cpu.mem_read(begin + 2);
+            let address_lo = cpu.peek(begin.wrapping_add(1));
+            let address_hi = cpu.peek(begin.wrapping_add(2));
             hex_dump.push(address_lo);
             hex_dump.push(address_hi);
 
-            let address = cpu.mem_read_u16(begin + 1);
+            let address = cpu.peek_u16(begin.wrapping_add(1));
 
             match ops.mode {
                 AddressingMode::NoneAddressing => {
                     if ops.code == 0x6c {
                         let jmp_addr = if address & 0x00FF == 0x00FF {
-                            let lo = cpu.mem_read(address);
-                            let hi = cpu.mem_read(address & 0xFF00);
+                            let lo = cpu.peek(address);
+                            let hi = cpu.peek(address & 0xFF00);
                             (hi as u16) << 8 | (lo as u16)
                         } else {
-                            cpu.mem_read_u16(address)
+                            cpu.peek_u16(address)
                         };
                         format!("(${:04x}) = {:04x}", address, jmp_addr)
                     } else {
@@ -84,13 +125,108 @@ pub fn trace(cpu: CPU) -> String {
         _ => String::from(""),
     };
 
+    let hex_str = hex_dump
+        .iter()
+        .map(|z| format!("{:02x}", z))
+        .collect::<Vec<String>>()
+        .join(" ");
+    let mut asm_str = format!("{:04x}  {:8} {: >4} {}", begin, hex_str, ops.mnemonic, tmp)
+        .trim()
+        .to_ascii_uppercase();
+
+    if let Some(symbols) = symbols {
+        // the address as written in the operand, which is what a label replaces
+        let operand = match (ops.len, &ops.mode) {
+            (2, AddressingMode::Immediate) => None,
+            (2, AddressingMode::NoneAddressing) => {
+                let target = begin.wrapping_add(2).wrapping_add((cpu.peek(begin.wrapping_add(1)) as i8) as u16);
+                Some((target, format!("${:04X}", target)))
+            }
+            (2, _) => {
+                let zero_page = cpu.peek(begin.wrapping_add(1));
+                Some((zero_page as u16, format!("${:02X}", zero_page)))
+            }
+            (3, _) => {
+                let address = cpu.peek_u16(begin.wrapping_add(1));
+                Some((address, format!("${:04X}", address)))
+            }
+            _ => None,
+        };
+        if let Some((addr, needle)) = operand {
+            if let Some(label) = symbols.label(&cpu.bus, addr) {
+                asm_str = asm_str.replacen(&needle, label, 1);
+            }
+        }
+    }
+
+    let (scanline, dot) = cpu.bus.ppu_position();
+
+    format!(
+        "{:47} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PPU:{:3},{:3} CYC:{}",
+        asm_str,
+        cpu.register_a,
+        cpu.register_x,
+        cpu.register_y,
+        cpu.status.bits(),
+        cpu.stack_pointer,
+        scanline,
+        dot,
+        cpu.cycles,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::test::test_rom;
+    use crate::cpu::FlatMemory;
+
     #[test]
-    fn test_format_mem_access() {
-        let mut bus = Bus::new(test_rom());
+    fn test_format_trace() {
+        let mut mem = FlatMemory::new();
+        mem.mem_write(100, 0xa2);
+        mem.mem_write(101, 0x01);
+        mem.mem_write(102, 0xca);
+        mem.mem_write(103, 0x88);
+        mem.mem_write(104, 0x00);
 
+        let mut cpu = CPU::new(mem);
+        cpu.program_counter = 0x64;
+        cpu.register_a = 1;
+        cpu.register_x = 2;
+        cpu.register_y = 3;
+        let mut result: Vec<String> = vec![];
+        cpu.run_with_callback(|cpu| {
+            result.push(trace(cpu));
+            if result.len() == 4 {
+                cpu.halt();
+            }
+        });
+        assert_eq!(
+            "0064  A2 01     LDX #$01                        A:01 X:02 Y:03 P:24 SP:FD PPU:  0,  0 CYC:0",
+            result[0]
+        );
+        assert_eq!(
+            "0066  CA        DEX                             A:01 X:01 Y:03 P:24 SP:FD PPU:  0,  0 CYC:2",
+            result[1]
+        );
+        assert_eq!(
+            "0067  88        DEY                             A:01 X:00 Y:03 P:26 SP:FD PPU:  0,  0 CYC:4",
+            result[2]
+        );
+    }
 
+    #[test]
+    fn test_format_mem_access() {
+        let mut bus = Bus::new(test_rom(), |_, _| {});
+        // ORA ($33),Y
         bus.mem_write(100, 0x11);
         bus.mem_write(101, 0x33);
+
+        bus.mem_write(0x33, 00);
+        bus.mem_write(0x34, 04);
+
         bus.mem_write(0x400, 0xAA);
 
         let mut cpu = CPU::new(bus);
@@ -99,7 +235,75 @@ pub fn trace(cpu: CPU) -> String {
         let mut result: Vec<String> = vec![];
         cpu.run_with_callback(|cpu| {
             result.push(trace(cpu));
+            cpu.halt();
         });
+        assert_eq!(
+            "0064  11 33     ORA ($33),Y = 0400 @ 0400 = AA  A:00 X:00 Y:00 P:24 SP:FD PPU:  0,  0 CYC:0",
+            result[0]
+        );
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn test_format_jumps_and_branches() {
+        let mut mem = FlatMemory::new();
+        // BNE +4
+        mem.mem_write(0x0200, 0xd0);
+        mem.mem_write(0x0201, 0x04);
+        // JMP ($02ff)
+        mem.mem_write(0x0206, 0x6c);
+        mem.mem_write_u16(0x0207, 0x02ff);
+        mem.mem_write(0x02ff, 0x10);
+        // STA $2002
+        mem.mem_write(0xd010, 0x8d);
+        mem.mem_write_u16(0xd011, 0x2002);
+        mem.mem_write(0x2002, 0x80);
+
+        let mut cpu = CPU::new(mem);
+        cpu.program_counter = 0x0200;
+        let mut result: Vec<String> = vec![];
+        cpu.run_with_callback(|cpu| {
+            result.push(trace(cpu));
+            if result.len() == 3 {
+                cpu.halt();
+            }
+        });
+
+        assert!(result[0].starts_with("0200  D0 04     BNE $0206 "));
+        // the pointer's high byte wraps to $0200 instead of $0300
+        assert!(result[1].starts_with("0206  6C FF 02  JMP ($02FF) = D010 "));
+        assert!(result[2].starts_with("D010  8D 02 20  STA $2002 = 80 "));
+    }
+
+    #[test]
+    fn test_format_wraps_at_end_of_memory() {
+        let mut mem = FlatMemory::new();
+        // LDA $1234 with its operand running past $FFFF
+        mem.mem_write(0xfffe, 0xad);
+        mem.mem_write(0xffff, 0x34);
+        mem.mem_write(0x0000, 0x12);
+        mem.mem_write(0x1234, 0x56);
+
+        let mut cpu = CPU::new(mem);
+        cpu.program_counter = 0xfffe;
+        assert!(trace(&cpu).starts_with("FFFE  AD 34 12  LDA $1234 = 56 "));
+    }
+
+    #[test]
+    fn test_format_with_symbols() {
+        let mut mem = FlatMemory::new();
+        // JSR $0300; LDA $10
+        mem.load(0x0200, &[0x20, 0x00, 0x03]);
+        mem.load(0x0300, &[0xa5, 0x10]);
+        let mut symbols = Symbols::new();
+        symbols.load_nl("$0300#UpdateSprites#\n$0010#scroll_x#\n", None);
+
+        let mut cpu = CPU::new(mem);
+        cpu.program_counter = 0x0200;
+        assert_eq!(
+            trace_with_symbols(&cpu, &symbols),
+            "0200  20 00 03  JSR UpdateSprites               A:00 X:00 Y:00 P:24 SP:FD PPU:  0,  0 CYC:0"
+        );
+        cpu.program_counter = 0x0300;
+        assert!(trace_with_symbols(&cpu, &symbols).starts_with("0300  A5 10     LDA scroll_x = 00 "));
+    }
+}
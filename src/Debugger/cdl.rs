@@ -0,0 +1,294 @@
+use crate::cpu::{AddressingMode, Mem};
+use crate::opcodes::OpCode;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+bitflags! {
+    /// What a PRG ROM byte has been seen doing. The low bits match the FCEUX
+    /// .cdl format; `OPERAND` is ours and is written out as code.
+    pub struct PrgFlags: u8 {
+        const CODE          = 0b0000_0001;
+        const DATA          = 0b0000_0010;
+        const INDIRECT_CODE = 0b0001_0000;
+        const INDIRECT_DATA = 0b0010_0000;
+        const PCM           = 0b0100_0000;
+        const OPERAND       = 0b1000_0000;
+    }
+}
+
+bitflags! {
+    /// How a CHR byte has been fetched. FCEUX only records "drawn" and
+    /// "read through $2007"; which pipeline drew it is kept for us.
+    pub struct ChrFlags: u8 {
+        const READ       = 0b0000_0010;
+        const BACKGROUND = 0b0001_0000;
+        const SPRITE     = 0b0010_0000;
+    }
+}
+
+/// FCEUX keeps the $8000/$A000/$C000/$E000 window a byte was seen through
+/// in bits 2-3.
+const PRG_BANK_BITS: u8 = 0b0000_1100;
+const FCEUX_CHR_DRAWN: u8 = 0b0000_0001;
+
+/// Code/data log over PRG and CHR ROM, for coverage and for telling code
+/// from data when disassembling. The CPU reports each instruction through
+/// `Mem::log_fetch` before it executes, so operand reads aren't taken for
+/// data; any other PRG read is data. ROM access itself isn't hooked: only
+/// the CPU knows which reads are fetches, so bytes are located through
+/// `Mem::prg_rom_offset`, the same mapping `Bus` reads ROM through.
+pub struct CodeDataLog {
+    prg: Vec<u8>,
+    chr: Vec<u8>,
+    fetch_addr: u16,
+    fetch_len: u8,
+    indirect_data: bool,
+    indirect_jump: bool,
+}
+
+impl CodeDataLog {
+    pub fn new(prg_len: usize, chr_len: usize) -> Self {
+        CodeDataLog {
+            prg: vec![0; prg_len],
+            chr: vec![0; chr_len],
+            fetch_addr: 0,
+            fetch_len: 0,
+            indirect_data: false,
+            indirect_jump: false,
+        }
+    }
+
+    pub fn prg(&self, offset: usize) -> PrgFlags {
+        PrgFlags::from_bits_truncate(self.prg.get(offset).cloned().unwrap_or(0))
+    }
+
+    pub fn chr(&self, offset: usize) -> ChrFlags {
+        ChrFlags::from_bits_truncate(self.chr.get(offset).cloned().unwrap_or(0))
+    }
+
+    /// Marks the PRG byte at `offset`, seen by the CPU at `addr`.
+    pub fn log_prg(&mut self, offset: usize, addr: u16, flags: PrgFlags) {
+        if let Some(byte) = self.prg.get_mut(offset) {
+            let bank = ((addr >> 13) & 0b11) as u8;
+            *byte = (*byte & !PRG_BANK_BITS) | (bank << 2) | flags.bits();
+        }
+    }
+
+    /// Marks `len` CHR bytes from `offset`, e.g. the 16 bytes of a tile.
+    pub fn log_chr(&mut self, offset: usize, len: usize, flags: ChrFlags) {
+        let end = (offset + len).min(self.chr.len());
+        for byte in self.chr[offset.min(end)..end].iter_mut() {
+            *byte |= flags.bits();
+        }
+    }
+
+    /// The instruction at `addr` is about to run: its opcode byte is code,
+    /// the rest are operands, and reads of them while it runs aren't data.
+    pub fn fetch<M: Mem>(&mut self, mem: &M, addr: u16, opcode: &OpCode) {
+        for i in 0..opcode.len {
+            let byte_addr = addr.wrapping_add(i as u16);
+            if let Some(offset) = mem.prg_rom_offset(byte_addr) {
+                let flags = match i {
+                    0 if self.indirect_jump => PrgFlags::CODE | PrgFlags::INDIRECT_CODE,
+                    0 => PrgFlags::CODE,
+                    _ => PrgFlags::OPERAND,
+                };
+                self.log_prg(offset, byte_addr, flags);
+            }
+        }
+        self.fetch_addr = addr;
+        self.fetch_len = opcode.len;
+        self.indirect_data = match opcode.mode {
+            AddressingMode::Indirect_X | AddressingMode::Indirect_Y => true,
+            _ => false,
+        };
+        self.indirect_jump = opcode.code == 0x6c;
+    }
+
+    /// An interrupt handler is reached through its vector, not through the
+    /// `JMP ($xxxx)` that may have run just before it.
+    pub fn interrupt(&mut self) {
+        self.indirect_jump = false;
+    }
+
+    /// A CPU read of `addr` that wasn't an instruction fetch.
+    pub fn read<M: Mem>(&mut self, mem: &M, addr: u16) {
+        if addr.wrapping_sub(self.fetch_addr) < self.fetch_len as u16 {
+            return;
+        }
+        if let Some(offset) = mem.prg_rom_offset(addr) {
+            let flags = if self.indirect_data {
+                PrgFlags::DATA | PrgFlags::INDIRECT_DATA
+            } else {
+                PrgFlags::DATA
+            };
+            self.log_prg(offset, addr, flags);
+        }
+    }
+
+    /// (code, data, total) PRG bytes, counting operands as code.
+    pub fn prg_coverage(&self) -> (usize, usize, usize) {
+        let code = PrgFlags::CODE | PrgFlags::OPERAND;
+        let code_bytes = self.prg.iter().filter(|b| PrgFlags::from_bits_truncate(**b).intersects(code)).count();
+        let data_bytes = self.prg.iter().filter(|b| *b & PrgFlags::DATA.bits() != 0).count();
+        (code_bytes, data_bytes, self.prg.len())
+    }
+
+    /// The log in FCEUX .cdl layout: one byte per PRG ROM byte followed by
+    /// one per CHR ROM byte.
+    pub fn to_fceux(&self) -> Vec<u8> {
+        let prg = self.prg.iter().map(|byte| {
+            let flags = PrgFlags::from_bits_truncate(*byte);
+            let mut out = *byte & !PrgFlags::OPERAND.bits();
+            if flags.contains(PrgFlags::OPERAND) {
+                out |= PrgFlags::CODE.bits();
+            }
+            out
+        });
+        let chr = self.chr.iter().map(|byte| {
+            let flags = ChrFlags::from_bits_truncate(*byte);
+            let mut out = *byte & ChrFlags::READ.bits();
+            if flags.intersects(ChrFlags::BACKGROUND | ChrFlags::SPRITE) {
+                out |= FCEUX_CHR_DRAWN;
+            }
+            out
+        });
+        prg.chain(chr).collect()
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, self.to_fceux())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::cpu::{interrupt, FlatMemory, CPU};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// 16 KiB of PRG mirrored at $8000 and $C000, logged the way `Bus` does.
+    struct Cart {
+        memory: FlatMemory,
+        cdl: Rc<RefCell<CodeDataLog>>,
+    }
+
+    impl Mem for Cart {
+        fn mem_read(&mut self, addr: u16) -> u8 {
+            self.cdl.borrow_mut().read(self, addr);
+            self.memory.mem_read(addr)
+        }
+        fn mem_write(&mut self, addr: u16, data: u8) {
+            self.memory.mem_write(addr, data)
+        }
+        fn peek(&self, addr: u16) -> u8 {
+            self.memory.peek(addr)
+        }
+        fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+            if addr >= 0x8000 {
+                Some(addr as usize & 0x3fff)
+            } else {
+                None
+            }
+        }
+        fn read_opcode(&mut self, addr: u16) -> u8 {
+            self.memory.mem_read(addr)
+        }
+        fn log_fetch(&mut self, addr: u16, opcode: &OpCode) {
+            self.cdl.borrow_mut().fetch(self, addr, opcode);
+        }
+        fn log_interrupt(&mut self) {
+            self.cdl.borrow_mut().interrupt();
+        }
+    }
+
+    #[test]
+    fn test_code_operand_and_data() {
+        let program = assemble(
+            "ldx #1\n\
+             lda table,x\n\
+             ldy #0\n\
+             lda ($10),y\n\
+             jmp (vector)\n\
+             target: nop\n\
+             table: .db $10, $20\n\
+             vector: .db $0c, $c0\n",
+            0xc000,
+        )
+        .unwrap();
+
+        let cdl = Rc::new(RefCell::new(CodeDataLog::new(0x4000, 0x2000)));
+        let mut memory = FlatMemory::new();
+        memory.load(0xc000, &program);
+        memory.load(0x0010, &[0x0d, 0xc0]);
+        let mut cpu = CPU::new(Cart { memory: memory, cdl: cdl.clone() });
+        cpu.program_counter = 0xc000;
+        for _ in 0..6 {
+            cpu.step();
+        }
+
+        let cdl = cdl.borrow();
+        assert_eq!(cdl.prg(0x0000), PrgFlags::CODE);
+        assert_eq!(cdl.prg(0x0001), PrgFlags::OPERAND);
+        assert_eq!(cdl.prg(0x000c), PrgFlags::CODE | PrgFlags::INDIRECT_CODE);
+        assert_eq!(cdl.prg(0x000d), PrgFlags::DATA | PrgFlags::INDIRECT_DATA);
+        assert_eq!(cdl.prg(0x000e), PrgFlags::DATA);
+        assert_eq!(cdl.prg(0x000f), PrgFlags::DATA);
+        assert_eq!(cdl.prg(0x0011), PrgFlags::empty());
+
+        let fceux = cdl.to_fceux();
+        assert_eq!(fceux.len(), 0x6000);
+        // seen through the $C000 window, so bank bits are %10
+        assert_eq!(fceux[0x0000], 0x09);
+        assert_eq!(fceux[0x0001], 0x09);
+        assert_eq!(fceux[0x000d], 0x2a);
+        assert_eq!(cdl.prg_coverage(), (13, 4, 0x4000));
+    }
+
+    #[test]
+    fn test_nmi_after_indirect_jump() {
+        let program = assemble(
+            "jmp (vector)\n\
+             target: nop\n\
+             handler: nop\n\
+             vector: .db $03, $c0\n",
+            0xc000,
+        )
+        .unwrap();
+
+        let cdl = Rc::new(RefCell::new(CodeDataLog::new(0x4000, 0x2000)));
+        let mut memory = FlatMemory::new();
+        memory.load(0xc000, &program);
+        memory.load(0xfffa, &[0x04, 0xc0]);
+        let mut cpu = CPU::new(Cart { memory: memory, cdl: cdl.clone() });
+        cpu.program_counter = 0xc000;
+        cpu.step();
+        cpu.interrupt(interrupt::NMI);
+        cpu.step();
+
+        let cdl = cdl.borrow();
+        assert_eq!(cdl.prg(0x0000), PrgFlags::CODE);
+        assert_eq!(cdl.prg(0x0003), PrgFlags::empty());
+        assert_eq!(cdl.prg(0x0004), PrgFlags::CODE);
+    }
+
+    #[test]
+    fn test_chr_export() {
+        let mut cdl = CodeDataLog::new(0, 0x2000);
+        cdl.log_chr(0x0010, 16, ChrFlags::BACKGROUND);
+        cdl.log_chr(0x1000, 16, ChrFlags::SPRITE);
+        cdl.log_chr(0x0018, 1, ChrFlags::READ);
+        cdl.log_chr(0x1ff8, 16, ChrFlags::SPRITE);
+
+        let fceux = cdl.to_fceux();
+        assert_eq!(fceux[0x000f], 0);
+        assert_eq!(fceux[0x0010], 0x01);
+        assert_eq!(fceux[0x0018], 0x03);
+        assert_eq!(fceux[0x1000], 0x01);
+        assert_eq!(fceux[0x1fff], 0x01);
+        assert_eq!(cdl.chr(0x1000), ChrFlags::SPRITE);
+    }
+}
@@ -0,0 +1,739 @@
+use crate::assembler::assemble;
+use crate::cpu::{BusAccess, Mem, CPU};
+use crate::disasm::disassemble;
+use crate::expr::Expr;
+use crate::symbols::Symbols;
+use crate::trace::trace_with_symbols;
+use std::cell::RefCell;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::rc::Rc;
+
+const JSR: u8 = 0x20;
+const RTS: u8 = 0x60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    Access,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub start: u16,
+    pub end: u16,
+    pub kind: WatchKind,
+}
+
+impl Watchpoint {
+    /// RAM and PPU register mirrors count as the address they mirror, so a
+    /// watch on $2002 also fires for a read of $3FFA. Instruction fetches
+    /// never match: running through watched code isn't a data access.
+    fn matches(&self, access: &BusAccess) -> bool {
+        let (addr, write) = match *access {
+            BusAccess::Fetch { .. } => return false,
+            BusAccess::Read { addr, .. } => (addr, false),
+            BusAccess::Write { addr, .. } => (addr, true),
+        };
+        let kind_matches = match self.kind {
+            WatchKind::Read => !write,
+            WatchKind::Write => write,
+            WatchKind::Access => true,
+        };
+        let canonical = match addr {
+            0x0800..=0x1fff => addr & 0x07ff,
+            0x2008..=0x3fff => addr & 0x2007,
+            _ => addr,
+        };
+        let in_range = |a: u16| self.start <= a && a <= self.end;
+        kind_matches && (in_range(addr) || in_range(canonical))
+    }
+}
+
+/// Fires when PC reaches `addr` and `condition` holds; leaving out the address
+/// checks the condition after every instruction. A tracepoint logs a trace
+/// line instead of halting.
+#[derive(Debug, Clone)]
+pub struct Breakpoint {
+    pub addr: Option<u16>,
+    pub condition: Option<Expr>,
+    pub trace: bool,
+}
+
+impl Breakpoint {
+    fn hits<M: Mem>(&self, cpu: &CPU<M>) -> bool {
+        self.addr.map_or(true, |addr| addr == cpu.program_counter)
+            && self.condition.as_ref().map_or(true, |cond| cond.is_true(cpu))
+    }
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", if self.trace { "trace" } else { "break" })?;
+        if let Some(addr) = self.addr {
+            write!(f, " ${:04X}", addr)?;
+        }
+        if let Some(cond) = &self.condition {
+            write!(f, " if {}", cond)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StopReason {
+    /// The requested step finished.
+    Step,
+    /// PC landed on a breakpoint.
+    Breakpoint(u16),
+    /// The last instruction touched a watched address; PC is already past it.
+    Watchpoint { index: usize, access: BusAccess },
+    /// Ran out of the instruction budget given to `run`.
+    Limit,
+}
+
+/// Drives a CPU one instruction at a time. Interrupts are polled before every
+/// instruction, the same as `run_with_callback`, so stepping never skips an
+/// NMI. Memory inspection goes through `peek` and never disturbs the PPU.
+pub struct Debugger<M: Mem> {
+    pub cpu: CPU<M>,
+    breakpoints: Vec<Breakpoint>,
+    watchpoints: Vec<Watchpoint>,
+    accesses: Rc<RefCell<Vec<BusAccess>>>,
+    trace_log: Vec<String>,
+    /// Labels for traces, disassembly, backtraces and address arguments.
+    pub symbols: Symbols,
+}
+
+impl<M: Mem> Debugger<M> {
+    pub fn new(mut cpu: CPU<M>) -> Self {
+        let accesses = Rc::new(RefCell::new(vec![]));
+        let sink = accesses.clone();
+        cpu.set_access_hook(move |access| sink.borrow_mut().push(access));
+
+        Debugger {
+            cpu: cpu,
+            breakpoints: vec![],
+            watchpoints: vec![],
+            accesses: accesses,
+            trace_log: vec![],
+            symbols: Symbols::new(),
+        }
+    }
+
+    pub fn add_breakpoint(&mut self, addr: u16) {
+        self.breakpoints.push(Breakpoint { addr: Some(addr), condition: None, trace: false });
+    }
+
+    /// Adds a breakpoint, or a tracepoint when `trace` is set, and returns its
+    /// index. `condition` is parsed as an `Expr`.
+    pub fn add_conditional(
+        &mut self,
+        addr: Option<u16>,
+        condition: Option<&str>,
+        trace: bool,
+    ) -> Result<usize, String> {
+        let condition = match condition {
+            Some(text) => Some(Expr::parse(text)?),
+            None => None,
+        };
+        self.breakpoints.push(Breakpoint { addr: addr, condition: condition, trace: trace });
+        Ok(self.breakpoints.len() - 1)
+    }
+
+    /// Removes every breakpoint and tracepoint at `addr`.
+    pub fn remove_breakpoint(&mut self, addr: u16) -> bool {
+        let before = self.breakpoints.len();
+        self.breakpoints.retain(|bp| bp.addr != Some(addr));
+        self.breakpoints.len() != before
+    }
+
+    pub fn delete_breakpoint(&mut self, index: usize) -> Option<Breakpoint> {
+        if index < self.breakpoints.len() {
+            Some(self.breakpoints.remove(index))
+        } else {
+            None
+        }
+    }
+
+    pub fn breakpoints(&self) -> &[Breakpoint] {
+        &self.breakpoints
+    }
+
+    /// Lines logged by tracepoints since the last call.
+    pub fn take_trace_log(&mut self) -> Vec<String> {
+        std::mem::replace(&mut self.trace_log, vec![])
+    }
+
+    /// Watches `start..=end` and returns the index to remove it with.
+    pub fn add_watchpoint(&mut self, start: u16, end: u16, kind: WatchKind) -> usize {
+        self.watchpoints.push(Watchpoint { start: start, end: end, kind: kind });
+        self.watchpoints.len() - 1
+    }
+
+    pub fn remove_watchpoint(&mut self, index: usize) -> Option<Watchpoint> {
+        if index < self.watchpoints.len() {
+            Some(self.watchpoints.remove(index))
+        } else {
+            None
+        }
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    /// Checks breakpoints against the instruction about to run, logging for
+    /// any tracepoints that hit.
+    fn check_breakpoints(&mut self) -> Option<StopReason> {
+        let mut stop = None;
+        let mut traced = false;
+        for bp in self.breakpoints.iter() {
+            if !bp.hits(&self.cpu) {
+                continue;
+            }
+            if bp.trace {
+                if !traced {
+                    self.trace_log.push(trace_with_symbols(&self.cpu, &self.symbols));
+                    traced = true;
+                }
+            } else if stop.is_none() {
+                stop = Some(StopReason::Breakpoint(self.cpu.program_counter));
+            }
+        }
+        stop
+    }
+
+    /// Checks watchpoints against the accesses made since the last clear.
+    fn check_watchpoints(&self) -> Option<StopReason> {
+        for access in self.accesses.borrow().iter() {
+            if let Some(index) = self.watchpoints.iter().position(|w| w.matches(access)) {
+                return Some(StopReason::Watchpoint { index: index, access: *access });
+            }
+        }
+        None
+    }
+
+    /// Services a pending interrupt, stopping if a breakpoint sits on the
+    /// handler, then executes one instruction and checks watchpoints against
+    /// what it touched and breakpoints against where it landed. Returns the
+    /// opcode that ran. The instruction at the starting PC always runs, so
+    /// resuming from a breakpoint makes progress.
+    fn execute(&mut self) -> Result<u8, StopReason> {
+        self.accesses.borrow_mut().clear();
+        if self.cpu.poll_interrupts() {
+            let watch = self.check_watchpoints();
+            let breakpoint = self.check_breakpoints();
+            if let Some(reason) = watch.or(breakpoint) {
+                return Err(reason);
+            }
+            self.accesses.borrow_mut().clear();
+        }
+        let opcode = self.cpu.step();
+
+        let watch = self.check_watchpoints();
+        let breakpoint = self.check_breakpoints();
+        match watch.or(breakpoint) {
+            Some(reason) => Err(reason),
+            None => Ok(opcode),
+        }
+    }
+
+    /// Runs until `done` says so, a breakpoint or watchpoint hits, or
+    /// `max_instructions` have executed.
+    fn run_until<F>(&mut self, max_instructions: usize, mut done: F) -> StopReason
+    where
+        F: FnMut(&CPU<M>, u8) -> bool,
+    {
+        for _ in 0..max_instructions {
+            match self.execute() {
+                Err(reason) => return reason,
+                Ok(opcode) if done(&self.cpu, opcode) => return StopReason::Step,
+                Ok(_) => {}
+            }
+        }
+        StopReason::Limit
+    }
+
+    pub fn step(&mut self) -> StopReason {
+        self.execute().err().unwrap_or(StopReason::Step)
+    }
+
+    /// Like `step`, except a JSR runs until the subroutine returns to the
+    /// instruction after it.
+    pub fn step_over(&mut self, max_instructions: usize) -> StopReason {
+        let pc = self.cpu.program_counter;
+        if self.cpu.peek(pc) != JSR {
+            return self.step();
+        }
+        let return_addr = pc.wrapping_add(3);
+        let sp = self.cpu.stack_pointer;
+        // the SP check keeps recursion from stopping at an inner return
+        self.run_until(max_instructions, |cpu, _| {
+            cpu.program_counter == return_addr && cpu.stack_pointer == sp
+        })
+    }
+
+    /// Runs until the current subroutine executes its RTS. Nested calls are
+    /// tracked by depth, so their returns don't count.
+    pub fn run_to_return(&mut self, max_instructions: usize) -> StopReason {
+        let mut depth = 0;
+        self.run_until(max_instructions, |_, opcode| match opcode {
+            JSR => {
+                depth += 1;
+                false
+            }
+            RTS if depth == 0 => true,
+            RTS => {
+                depth -= 1;
+                false
+            }
+            _ => false,
+        })
+    }
+
+    /// Runs until a breakpoint or watchpoint hits.
+    pub fn run(&mut self, max_instructions: usize) -> StopReason {
+        self.run_until(max_instructions, |_, _| false)
+    }
+
+    pub fn registers(&self) -> String {
+        let cpu = &self.cpu;
+        format!(
+            "PC:{:04X} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} {} SP:{:02X} CYC:{}",
+            cpu.program_counter,
+            cpu.register_a,
+            cpu.register_x,
+            cpu.register_y,
+            cpu.status.bits(),
+            cpu.status,
+            cpu.stack_pointer,
+            cpu.cycles,
+        )
+    }
+
+    /// The CPU's shadow call stack, labelled from `symbols`.
+    pub fn backtrace(&self) -> String {
+        let bus = &self.cpu.bus;
+        self.cpu
+            .call_stack
+            .backtrace(self.cpu.program_counter, |addr| self.symbols.label(bus, addr).map(|s| s.to_string()))
+    }
+
+    pub fn memory(&self, start: u16, len: u16) -> Vec<u8> {
+        (0..len).map(|i| self.cpu.peek(start.wrapping_add(i))).collect()
+    }
+
+    /// Sixteen bytes per row, each row prefixed with its address.
+    pub fn hexdump(&self, start: u16, len: u16) -> String {
+        self.memory(start, len)
+            .chunks(16)
+            .enumerate()
+            .map(|(row, bytes)| {
+                let hex: Vec<String> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+                format!("{:04X}  {}", start.wrapping_add(row as u16 * 16), hex.join(" "))
+            })
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
+    /// Assembles `source` at `addr` and pokes it straight into the bus, so
+    /// code in ROM can be patched and the patch doesn't trip watchpoints.
+    /// Returns the number of bytes written.
+    pub fn patch(&mut self, addr: u16, source: &str) -> Result<usize, String> {
+        let bytes = assemble(source, addr).map_err(|e| e.to_string())?;
+        for (i, byte) in bytes.iter().enumerate() {
+            let target = addr.wrapping_add(i as u16);
+            if !self.cpu.bus.poke(target, *byte) {
+                return Err(format!("can't patch ${:04X}", target));
+            }
+        }
+        Ok(bytes.len())
+    }
+}
+
+fn parse_count(text: Option<&&str>, default: usize) -> Result<usize, String> {
+    match text {
+        Some(t) => t.parse().map_err(|_| format!("bad count '{}'", t)),
+        None => Ok(default),
+    }
+}
+
+const HELP: &str = "\
+s [n]            step n instructions
+n                step over JSR
+f                run to RTS
+c [n]            continue (at most n instructions)
+b [addr] [if <expr>]                  set breakpoint
+t [addr] [if <expr>]                  set tracepoint
+d <addr>         delete at address    db <index> delete break/tracepoint
+e <expr>         evaluate expression  bt         backtrace
+sym <file>       load .dbg/.nl/.mlb symbols; labels work anywhere an address does
+w <addr>[-<end>] [r|w|rw]             watch addresses
+dw <index>       delete watchpoint    l          list break/watchpoints
+r                registers            m <addr> [len]   dump memory
+u [addr] [n]     disassemble          p <addr> <asm>   assemble a patch
+q                quit";
+
+/// Instruction budget for commands that run until something happens.
+const RUN_LIMIT: usize = 10_000_000;
+
+impl<M: Mem> Debugger<M> {
+    /// Hex with or without a leading `$`, or a label from the loaded symbols.
+    fn parse_addr(&self, text: &str) -> Result<u16, String> {
+        if let Some(addr) = self.symbols.address(&self.cpu.bus, text) {
+            return Ok(addr);
+        }
+        let digits = text.trim_start_matches('$');
+        u16::from_str_radix(digits, 16).map_err(|_| format!("bad address '{}'", text))
+    }
+
+    /// Splits `[addr] [if <expr>]` off a b/t command line.
+    fn parse_point<'l>(&self, args: &[&str], line: &'l str) -> Result<(Option<u16>, Option<&'l str>), String> {
+        let condition = line.find(" if ").map(|idx| line[idx + 4..].trim());
+        let addr = match args.get(0) {
+            Some(&"if") | None => None,
+            Some(text) => Some(self.parse_addr(text)?),
+        };
+        Ok((addr, condition))
+    }
+
+    fn report<W: Write>(&mut self, out: &mut W, reason: StopReason) -> io::Result<()> {
+        for line in self.take_trace_log() {
+            writeln!(out, "{}", line)?;
+        }
+        match reason {
+            StopReason::Step => {}
+            StopReason::Breakpoint(addr) => writeln!(out, "breakpoint at ${:04X}", addr)?,
+            StopReason::Watchpoint { index, access } => match access {
+                BusAccess::Read { addr, value } | BusAccess::Fetch { addr, value } => {
+                    writeln!(out, "watchpoint {}: read ${:04X} = {:02X}", index, addr, value)?
+                }
+                BusAccess::Write { addr, value } => {
+                    writeln!(out, "watchpoint {}: write ${:04X} = {:02X}", index, addr, value)?
+                }
+            },
+            StopReason::Limit => writeln!(out, "instruction limit reached")?,
+        }
+        writeln!(out, "{}", trace_with_symbols(&self.cpu, &self.symbols))
+    }
+
+    /// Runs one REPL command. Returns Ok(false) once the user quits.
+    pub fn command<W: Write>(&mut self, line: &str, out: &mut W) -> io::Result<bool> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let result = match words.first() {
+            None => Ok(()),
+            Some(&"q") | Some(&"quit") => return Ok(false),
+            Some(&"h") | Some(&"help") => writeln!(out, "{}", HELP).map_err(|e| e.to_string()),
+            Some(cmd) => self.dispatch(cmd, &words[1..], line, out),
+        };
+        if let Err(message) = result {
+            writeln!(out, "error: {}", message)?;
+        }
+        Ok(true)
+    }
+
+    fn dispatch<W: Write>(&mut self, cmd: &str, args: &[&str], line: &str, out: &mut W) -> Result<(), String> {
+        let io_err = |e: io::Error| e.to_string();
+        match cmd {
+            "s" | "step" => {
+                let mut reason = StopReason::Step;
+                for _ in 0..parse_count(args.get(0), 1)? {
+                    reason = self.step();
+                    if reason != StopReason::Step {
+                        break;
+                    }
+                }
+                self.report(out, reason).map_err(io_err)
+            }
+            "n" | "next" => {
+                let reason = self.step_over(RUN_LIMIT);
+                self.report(out, reason).map_err(io_err)
+            }
+            "f" | "finish" => {
+                let reason = self.run_to_return(RUN_LIMIT);
+                self.report(out, reason).map_err(io_err)
+            }
+            "c" | "continue" => {
+                let reason = self.run(parse_count(args.get(0), RUN_LIMIT)?);
+                self.report(out, reason).map_err(io_err)
+            }
+            "b" | "break" | "t" | "trace" => {
+                let (addr, condition) = self.parse_point(args, line)?;
+                if addr.is_none() && condition.is_none() && cmd.starts_with('b') {
+                    return Err(String::from("b needs an address or a condition"));
+                }
+                let index = self.add_conditional(addr, condition, cmd.starts_with('t'))?;
+                writeln!(out, "#{} {}", index, self.breakpoints[index]).map_err(io_err)
+            }
+            "db" => {
+                let index = parse_count(args.get(0), 0)?;
+                self.delete_breakpoint(index).map(|_| ()).ok_or(format!("no breakpoint #{}", index))
+            }
+            "e" | "eval" => {
+                let source = line.splitn(2, char::is_whitespace).nth(1).unwrap_or("");
+                let value = Expr::parse(source)?.eval(&self.cpu);
+                writeln!(out, "{} (${:X})", value, value).map_err(io_err)
+            }
+            "d" | "delete" => {
+                let addr = self.parse_addr(args.get(0).ok_or("d needs an address")?)?;
+                if self.remove_breakpoint(addr) {
+                    Ok(())
+                } else {
+                    Err(format!("no breakpoint at ${:04X}", addr))
+                }
+            }
+            "w" | "watch" => {
+                let range = args.get(0).ok_or("w needs an address")?;
+                let (start, end) = match range.find('-') {
+                    Some(idx) => (self.parse_addr(&range[..idx])?, self.parse_addr(&range[idx + 1..])?),
+                    None => {
+                        let addr = self.parse_addr(range)?;
+                        (addr, addr)
+                    }
+                };
+                let kind = match args.get(1) {
+                    Some(&"r") => WatchKind::Read,
+                    Some(&"w") => WatchKind::Write,
+                    Some(&"rw") | None => WatchKind::Access,
+                    Some(other) => return Err(format!("bad watch kind '{}'", other)),
+                };
+                let index = self.add_watchpoint(start, end, kind);
+                writeln!(out, "watchpoint {}", index).map_err(io_err)
+            }
+            "dw" => {
+                let index = parse_count(args.get(0), 0)?;
+                self.remove_watchpoint(index).map(|_| ()).ok_or(format!("no watchpoint {}", index))
+            }
+            "l" | "list" => {
+                for (index, bp) in self.breakpoints.iter().enumerate() {
+                    writeln!(out, "#{} {}", index, bp).map_err(io_err)?;
+                }
+                for (index, w) in self.watchpoints.iter().enumerate() {
+                    writeln!(out, "watch {}: ${:04X}-${:04X} {:?}", index, w.start, w.end, w.kind)
+                        .map_err(io_err)?;
+                }
+                Ok(())
+            }
+            "r" | "regs" => writeln!(out, "{}", self.registers()).map_err(io_err),
+            "bt" | "backtrace" => writeln!(out, "{}", self.backtrace()).map_err(io_err),
+            "sym" => {
+                let path = args.get(0).ok_or("sym needs a .dbg, .nl or .mlb file")?;
+                let before = self.symbols.len();
+                self.symbols.load_file(path).map_err(io_err)?;
+                writeln!(out, "{} labels loaded", self.symbols.len() - before).map_err(io_err)
+            }
+            "m" | "mem" => {
+                let start = self.parse_addr(args.get(0).ok_or("m needs an address")?)?;
+                let len = parse_count(args.get(1), 64)? as u16;
+                writeln!(out, "{}", self.hexdump(start, len)).map_err(io_err)
+            }
+            "u" | "dis" => {
+                let start = match args.get(0) {
+                    Some(addr) => self.parse_addr(addr)?,
+                    None => self.cpu.program_counter,
+                };
+                let count = parse_count(args.get(1), 10)?;
+                for instruction in disassemble(&self.cpu.bus, start, 0xffff).with_symbols(&self.symbols).take(count) {
+                    writeln!(out, "{}", instruction).map_err(io_err)?;
+                }
+                Ok(())
+            }
+            "p" | "patch" => {
+                let addr = self.parse_addr(args.get(0).ok_or("p needs an address")?)?;
+                // everything after the address is assembler source
+                let source = line.split_whitespace().skip(2).collect::<Vec<&str>>().join(" ");
+                let len = self.patch(addr, &source)?;
+                writeln!(out, "{} bytes at ${:04X}", len, addr).map_err(io_err)
+            }
+            _ => Err(format!("unknown command '{}', try 'help'", cmd)),
+        }
+    }
+
+    /// Reads commands from `input` until it ends or the user quits.
+    pub fn repl<R: BufRead, W: Write>(&mut self, input: R, mut out: W) -> io::Result<()> {
+        writeln!(out, "{}", trace_with_symbols(&self.cpu, &self.symbols))?;
+        write!(out, "> ")?;
+        out.flush()?;
+        for line in input.lines() {
+            if !self.command(&line?, &mut out)? {
+                break;
+            }
+            write!(out, "> ")?;
+            out.flush()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::FlatMemory;
+
+    fn debugger(source: &str) -> Debugger<FlatMemory> {
+        let mut mem = FlatMemory::new();
+        mem.load(0x0600, &assemble(source, 0x0600).unwrap());
+        let mut cpu = CPU::new(mem);
+        cpu.program_counter = 0x0600;
+        Debugger::new(cpu)
+    }
+
+    const PROGRAM: &str = "
+              ldx #$03
+        loop: jsr sub
+              dex
+              bne loop
+              brk
+        sub:  lda $3ffa
+              sta $10
+              rts
+    ";
+
+    #[test]
+    fn test_breakpoint_and_step_over() {
+        let mut dbg = debugger(PROGRAM);
+        dbg.add_breakpoint(0x0605);
+
+        assert_eq!(dbg.run(100), StopReason::Breakpoint(0x0605));
+        assert_eq!(dbg.cpu.register_x, 3);
+        assert_eq!(dbg.run(100), StopReason::Breakpoint(0x0605));
+        assert_eq!(dbg.cpu.register_x, 2);
+
+        dbg.remove_breakpoint(0x0605);
+        // jump back to the JSR and step over it
+        dbg.step();
+        dbg.step();
+        assert_eq!(dbg.cpu.program_counter, 0x0602);
+        assert_eq!(dbg.step_over(100), StopReason::Step);
+        assert_eq!(dbg.cpu.program_counter, 0x0605);
+    }
+
+    #[test]
+    fn test_run_to_return() {
+        let mut dbg = debugger(PROGRAM);
+        dbg.step();
+        dbg.step();
+        assert_eq!(dbg.cpu.program_counter, 0x0609);
+
+        assert_eq!(dbg.run_to_return(100), StopReason::Step);
+        assert_eq!(dbg.cpu.program_counter, 0x0605);
+    }
+
+    #[test]
+    fn test_interrupts_before_breakpoints() {
+        let mut dbg = debugger(PROGRAM);
+        // NMI handler at $0700: NOP; RTI
+        dbg.cpu.bus.load(0x0700, &[0xea, 0x40]);
+        dbg.cpu.bus.load(0xfffa, &[0x00, 0x07]);
+        dbg.add_breakpoint(0x0700);
+
+        dbg.step();
+        dbg.cpu.bus.nmi_pending = true;
+        assert_eq!(dbg.run(100), StopReason::Breakpoint(0x0700));
+        assert_eq!(dbg.cpu.program_counter, 0x0700);
+        assert_eq!(dbg.step(), StopReason::Step);
+        assert_eq!(dbg.cpu.program_counter, 0x0701);
+        dbg.remove_breakpoint(0x0700);
+
+        // an NMI taken at the RTS runs its handler first; only the RTS that
+        // actually executes ends run_to_return
+        while dbg.cpu.program_counter != 0x060e {
+            dbg.step();
+        }
+        dbg.cpu.bus.nmi_pending = true;
+        assert_eq!(dbg.run_to_return(100), StopReason::Step);
+        assert_eq!(dbg.cpu.program_counter, 0x0605);
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let mut dbg = debugger(PROGRAM);
+        // the program reads PPUSTATUS through its $3FFA mirror
+        dbg.add_watchpoint(0x2002, 0x2002, WatchKind::Read);
+        dbg.add_watchpoint(0x0010, 0x001f, WatchKind::Write);
+
+        assert_eq!(
+            dbg.run(100),
+            StopReason::Watchpoint { index: 0, access: BusAccess::Read { addr: 0x3ffa, value: 0 } }
+        );
+        assert_eq!(dbg.cpu.program_counter, 0x060c);
+        assert_eq!(
+            dbg.run(100),
+            StopReason::Watchpoint { index: 1, access: BusAccess::Write { addr: 0x0010, value: 0 } }
+        );
+    }
+
+    #[test]
+    fn test_read_watch_ignores_fetches() {
+        let mut dbg = debugger(
+            "
+                  ldx #2
+            loop: dex
+                  bne loop
+                  lda loop
+                  brk
+            ",
+        );
+        dbg.add_watchpoint(0x0602, 0x0602, WatchKind::Read);
+
+        // the DEX at the watched address runs twice before LDA reads it
+        assert_eq!(
+            dbg.run(100),
+            StopReason::Watchpoint { index: 0, access: BusAccess::Read { addr: 0x0602, value: 0xca } }
+        );
+        assert_eq!(dbg.cpu.register_x, 0);
+        assert_eq!(dbg.cpu.program_counter, 0x0608);
+    }
+
+    #[test]
+    fn test_conditional_breakpoint_and_tracepoint() {
+        let mut dbg = debugger(PROGRAM);
+        dbg.add_conditional(Some(0x0605), Some("x == 1"), false).unwrap();
+        dbg.add_conditional(Some(0x0609), None, true).unwrap();
+        assert!(dbg.add_conditional(None, Some("x ="), false).is_err());
+
+        assert_eq!(dbg.run(100), StopReason::Breakpoint(0x0605));
+        assert_eq!(dbg.cpu.register_x, 1);
+
+        let log = dbg.take_trace_log();
+        assert_eq!(log.len(), 3);
+        assert!(log[2].starts_with("0609  AD FA 3F  LDA $3FFA = 00"));
+        assert!(dbg.take_trace_log().is_empty());
+
+        // no address: checked after every instruction
+        dbg.add_conditional(None, Some("[$10] == $ff && z"), false).unwrap();
+        dbg.cpu.mem_write(0x10, 0xff);
+        assert_eq!(dbg.run(100), StopReason::Breakpoint(0x0606));
+    }
+
+    #[test]
+    fn test_symbols_in_repl() {
+        let mut dbg = debugger(PROGRAM);
+        dbg.symbols.load_nl("$0609#sub#\n", None);
+        let mut out = vec![];
+        dbg.repl("s\nb sub\nc\nbt\n".as_bytes(), &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        assert!(out.contains("0602  20 09 06  JSR sub"));
+        assert!(out.contains("breakpoint at $0609"));
+        assert!(out.contains("#0  $0609 in sub"));
+    }
+
+    #[test]
+    fn test_repl_session() {
+        let mut dbg = debugger(PROGRAM);
+        let input = "b 605\nc\nr\np  605   inx\np 606 lda   #1\nm 605 3\nbogus\nq\ns\n";
+        let mut out = vec![];
+        dbg.repl(input.as_bytes(), &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        assert!(out.contains("breakpoint at $0605"));
+        assert!(out.contains("PC:0605 A:00 X:03"));
+        assert!(out.contains("0605  E8 A9 01"));
+        assert!(out.contains("error: unknown command 'bogus'"));
+        // nothing runs after quit
+        assert_eq!(dbg.cpu.program_counter, 0x0605);
+    }
+}
@@ -0,0 +1,356 @@
+use crate::cpu::{CpuFlags, Mem, CPU};
+use std::fmt;
+
+/// Something an expression can read out of the machine.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Var {
+    A,
+    X,
+    Y,
+    SP,
+    PC,
+    P,
+    Flag(CpuFlags),
+    Cycles,
+    Scanline,
+    Dot,
+    PpuCtrl,
+    PpuStatus,
+}
+
+fn lookup(name: &str) -> Option<Var> {
+    let var = match name.to_ascii_lowercase().as_str() {
+        "a" => Var::A,
+        "x" => Var::X,
+        "y" => Var::Y,
+        "sp" => Var::SP,
+        "pc" => Var::PC,
+        "p" => Var::P,
+        "c" => Var::Flag(CpuFlags::CARRY),
+        "z" => Var::Flag(CpuFlags::ZERO),
+        "i" => Var::Flag(CpuFlags::INTERRUPT_DISABLE),
+        "d" => Var::Flag(CpuFlags::DECIMAL_MODE),
+        "v" => Var::Flag(CpuFlags::OVERFLOW),
+        "n" => Var::Flag(CpuFlags::NEGATIVE),
+        "cycles" | "cyc" => Var::Cycles,
+        "scanline" => Var::Scanline,
+        "dot" => Var::Dot,
+        "ctrl" | "ppuctrl" => Var::PpuCtrl,
+        "status" | "ppustatus" => Var::PpuStatus,
+        _ => return None,
+    };
+    Some(var)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BinOp {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    BitOr,
+    BitXor,
+    BitAnd,
+    Add,
+    Sub,
+}
+
+#[derive(Debug, Clone)]
+enum Node {
+    Number(i64),
+    Var(Var),
+    /// `[addr]`, one byte read through `peek`.
+    Mem(Box<Node>),
+    Not(Box<Node>),
+    Neg(Box<Node>),
+    Binary(BinOp, Box<Node>, Box<Node>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(i64),
+    Ident(String),
+    Op(&'static str),
+}
+
+const OPS: [&str; 20] = [
+    "||", "&&", "==", "!=", "<=", ">=", "<", ">", "|", "^", "&", "+", "-", "!", "(", ")", "[", "]",
+    "=", "~",
+];
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = vec![];
+    let mut rest = text.trim_start();
+
+    while !rest.is_empty() {
+        let first = rest.chars().next().unwrap();
+        if first == '$' || first == '%' || first.is_ascii_digit() {
+            let (radix, body) = match first {
+                '$' => (16, &rest[1..]),
+                '%' => (2, &rest[1..]),
+                _ => (10, rest),
+            };
+            let len = body.find(|c: char| !c.is_ascii_alphanumeric()).unwrap_or(body.len());
+            let value = i64::from_str_radix(&body[..len], radix)
+                .map_err(|_| format!("bad number '{}'", &rest[..rest.len() - body.len() + len]))?;
+            tokens.push(Token::Number(value));
+            rest = &body[len..];
+        } else if first.is_ascii_alphabetic() || first == '_' {
+            let len = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            tokens.push(Token::Ident(rest[..len].to_string()));
+            rest = &rest[len..];
+        } else {
+            match OPS.iter().find(|op| rest.starts_with(*op)) {
+                // `=` and `~` are only in the table so they give a clear error
+                Some(&"=") => return Err(String::from("use '==' to compare")),
+                Some(&"~") => return Err(String::from("use '!' to negate")),
+                Some(op) => {
+                    tokens.push(Token::Op(op));
+                    rest = &rest[op.len()..];
+                }
+                None => return Err(format!("unexpected '{}'", first)),
+            }
+        }
+        rest = rest.trim_start();
+    }
+    Ok(tokens)
+}
+
+/// Precedence climbing, loosest first. Bitwise operators bind tighter than
+/// comparisons, as in Rust, so `status & $40 == $40` does what it looks like.
+const LEVELS: [&[(&str, BinOp)]; 6] = [
+    &[("||", BinOp::Or)],
+    &[("&&", BinOp::And)],
+    &[
+        ("==", BinOp::Eq),
+        ("!=", BinOp::Ne),
+        ("<=", BinOp::Le),
+        (">=", BinOp::Ge),
+        ("<", BinOp::Lt),
+        (">", BinOp::Gt),
+    ],
+    &[("|", BinOp::BitOr), ("^", BinOp::BitXor)],
+    &[("&", BinOp::BitAnd)],
+    &[("+", BinOp::Add), ("-", BinOp::Sub)],
+];
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek_op(&self) -> Option<&'static str> {
+        match self.tokens.get(self.pos) {
+            Some(Token::Op(op)) => Some(op),
+            _ => None,
+        }
+    }
+
+    fn expect(&mut self, op: &str) -> Result<(), String> {
+        if self.peek_op() == Some(op) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(format!("expected '{}'", op))
+        }
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Node, String> {
+        if level == LEVELS.len() {
+            return self.unary();
+        }
+        let mut left = self.binary(level + 1)?;
+        while let Some(op) = self.peek_op() {
+            let bin_op = match LEVELS[level].iter().find(|(text, _)| *text == op) {
+                Some((_, bin_op)) => *bin_op,
+                None => break,
+            };
+            self.pos += 1;
+            let right = self.binary(level + 1)?;
+            left = Node::Binary(bin_op, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Node, String> {
+        match self.peek_op() {
+            Some("!") => {
+                self.pos += 1;
+                Ok(Node::Not(Box::new(self.unary()?)))
+            }
+            Some("-") => {
+                self.pos += 1;
+                Ok(Node::Neg(Box::new(self.unary()?)))
+            }
+            _ => self.primary(),
+        }
+    }
+
+    fn primary(&mut self) -> Result<Node, String> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        match token {
+            Some(Token::Number(n)) => Ok(Node::Number(n)),
+            Some(Token::Ident(name)) => match lookup(&name) {
+                Some(var) => Ok(Node::Var(var)),
+                None => Err(format!("unknown name '{}'", name)),
+            },
+            Some(Token::Op("(")) => {
+                let inner = self.binary(0)?;
+                self.expect(")")?;
+                Ok(inner)
+            }
+            Some(Token::Op("[")) => {
+                let inner = self.binary(0)?;
+                self.expect("]")?;
+                Ok(Node::Mem(Box::new(inner)))
+            }
+            Some(Token::Op(op)) => Err(format!("unexpected '{}'", op)),
+            None => Err(String::from("unexpected end of expression")),
+        }
+    }
+}
+
+/// A debugger condition such as `status & $40 && scanline < 30` or
+/// `[$0300] == a`. Names are case-insensitive:
+///
+/// - registers `a x y sp pc p`, flags `c z i d v n` (0 or 1), `cycles`
+/// - PPU `scanline`, `dot`, `ctrl`, `status`
+/// - `[expr]` reads a byte with `peek`
+///
+/// Anything non-zero is true.
+#[derive(Debug, Clone)]
+pub struct Expr {
+    source: String,
+    root: Node,
+}
+
+impl Expr {
+    pub fn parse(text: &str) -> Result<Expr, String> {
+        let mut parser = Parser { tokens: tokenize(text)?, pos: 0 };
+        let root = parser.binary(0)?;
+        if parser.pos < parser.tokens.len() {
+            return Err(format!("trailing input in '{}'", text.trim()));
+        }
+        Ok(Expr { source: text.trim().to_string(), root: root })
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    pub fn eval<M: Mem>(&self, cpu: &CPU<M>) -> i64 {
+        eval(&self.root, cpu)
+    }
+
+    pub fn is_true<M: Mem>(&self, cpu: &CPU<M>) -> bool {
+        self.eval(cpu) != 0
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+fn read_var<M: Mem>(var: Var, cpu: &CPU<M>) -> i64 {
+    match var {
+        Var::A => cpu.register_a as i64,
+        Var::X => cpu.register_x as i64,
+        Var::Y => cpu.register_y as i64,
+        Var::SP => cpu.stack_pointer as i64,
+        Var::PC => cpu.program_counter as i64,
+        Var::P => cpu.status.bits() as i64,
+        Var::Flag(flag) => cpu.status.contains(flag) as i64,
+        Var::Cycles => cpu.cycles as i64,
+        Var::Scanline => cpu.bus.ppu_position().0 as i64,
+        Var::Dot => cpu.bus.ppu_position().1 as i64,
+        Var::PpuCtrl => cpu.bus.ppu_registers().0 as i64,
+        Var::PpuStatus => cpu.bus.ppu_registers().1 as i64,
+    }
+}
+
+fn eval<M: Mem>(node: &Node, cpu: &CPU<M>) -> i64 {
+    match node {
+        Node::Number(n) => *n,
+        Node::Var(var) => read_var(*var, cpu),
+        Node::Mem(addr) => cpu.peek(eval(addr, cpu) as u16) as i64,
+        Node::Not(inner) => (eval(inner, cpu) == 0) as i64,
+        Node::Neg(inner) => -eval(inner, cpu),
+        Node::Binary(op, left, right) => {
+            let l = eval(left, cpu);
+            // short-circuit so `[ptr] && ...` style guards don't read needlessly
+            match op {
+                BinOp::Or if l != 0 => return 1,
+                BinOp::And if l == 0 => return 0,
+                _ => {}
+            }
+            let r = eval(right, cpu);
+            match op {
+                BinOp::Or | BinOp::And => (r != 0) as i64,
+                BinOp::Eq => (l == r) as i64,
+                BinOp::Ne => (l != r) as i64,
+                BinOp::Lt => (l < r) as i64,
+                BinOp::Le => (l <= r) as i64,
+                BinOp::Gt => (l > r) as i64,
+                BinOp::Ge => (l >= r) as i64,
+                BinOp::BitOr => l | r,
+                BinOp::BitXor => l ^ r,
+                BinOp::BitAnd => l & r,
+                BinOp::Add => l.wrapping_add(r),
+                BinOp::Sub => l.wrapping_sub(r),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::FlatMemory;
+
+    fn eval_str(text: &str, cpu: &CPU<FlatMemory>) -> i64 {
+        Expr::parse(text).unwrap().eval(cpu)
+    }
+
+    #[test]
+    fn test_registers_flags_and_memory() {
+        let mut cpu = CPU::new(FlatMemory::new());
+        cpu.register_a = 0x42;
+        cpu.register_x = 3;
+        cpu.status.insert(CpuFlags::CARRY);
+        cpu.mem_write(0x0303, 0x42);
+
+        assert_eq!(eval_str("A", &cpu), 0x42);
+        assert_eq!(eval_str("[$0300 + x] == a", &cpu), 1);
+        assert_eq!(eval_str("c && !z", &cpu), 1);
+        assert_eq!(eval_str("p & %1", &cpu), 1);
+        assert_eq!(eval_str("a & $0f == 2", &cpu), 1);
+        assert_eq!(eval_str("(x - 4) < 0 || [$ffff]", &cpu), 1);
+    }
+
+    #[test]
+    fn test_ppu_state() {
+        let cpu = CPU::new(FlatMemory::new());
+        // FlatMemory has no PPU, so everything reads as power-on zero
+        assert_eq!(eval_str("status & $40 == 0 && scanline < 30", &cpu), 1);
+        assert_eq!(eval_str("ctrl | dot", &cpu), 0);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(Expr::parse("a = 1").is_err());
+        assert!(Expr::parse("[$0300").is_err());
+        assert!(Expr::parse("foo").is_err());
+        assert!(Expr::parse("a 1").is_err());
+        assert!(Expr::parse("").is_err());
+    }
+}
@@ -0,0 +1,547 @@
+use crate::cpu::{BusAccess, CpuFlags, Mem};
+use crate::debugger::{Debugger, StopReason, WatchKind};
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+/// Register numbers as the stub exposes them: name, size in bits and the
+/// role lldb knows it by. There's no 6502 target in gdb or lldb, so the
+/// client is told the layout through target.xml or qRegisterInfo. A, X, Y,
+/// P and SP are one byte each and PC is two bytes, little-endian, in that
+/// order.
+const REGISTERS: [(&str, usize, Option<&str>); 6] = [
+    ("a", 8, None),
+    ("x", 8, None),
+    ("y", 8, None),
+    ("p", 8, Some("flags")),
+    ("sp", 8, Some("sp")),
+    ("pc", 16, Some("pc")),
+];
+
+/// The most an `m` packet can ask for: the whole address space.
+const MAX_READ: usize = 0x10000;
+
+/// Instructions run between checks for a ^C from the client.
+const CONTINUE_CHUNK: usize = 10_000;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+fn checksum(data: &str) -> u8 {
+    data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b))
+}
+
+fn hex_bytes(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn parse_hex_bytes(text: &str) -> Option<Vec<u8>> {
+    if text.len() % 2 != 0 {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok())
+        .collect()
+}
+
+fn parse_hex(text: &str) -> Option<u16> {
+    u16::from_str_radix(text, 16).ok()
+}
+
+/// The register layout as a gdb target description, served as target.xml.
+fn target_xml() -> String {
+    let regs: Vec<String> = REGISTERS
+        .iter()
+        .enumerate()
+        .map(|(regnum, (name, bits, generic))| {
+            let kind = if *name == "pc" { "code_ptr" } else { "uint8" };
+            let generic = generic.map(|g| format!(" generic=\"{}\"", g)).unwrap_or_default();
+            format!(
+                "<reg name=\"{}\" bitsize=\"{}\" regnum=\"{}\" type=\"{}\" group=\"general\"{}/>",
+                name, bits, regnum, kind, generic
+            )
+        })
+        .collect();
+    format!(
+        "<?xml version=\"1.0\"?><!DOCTYPE target SYSTEM \"gdb-target.dtd\">\
+         <target version=\"1.0\"><feature name=\"org.nes.6502.core\">{}</feature></target>",
+        regs.concat()
+    )
+}
+
+/// lldb's qRegisterInfo reply for register `index`.
+fn register_info(index: usize) -> Option<String> {
+    let (name, bits, generic) = REGISTERS.get(index)?;
+    let offset: usize = REGISTERS[..index].iter().map(|(_, bits, _)| bits / 8).sum();
+    let generic = generic.map(|g| format!("generic:{};", g)).unwrap_or_default();
+    Some(format!(
+        "name:{};bitsize:{};offset:{};encoding:uint;format:hex;set:General Purpose Registers;{}",
+        name, bits, offset, generic
+    ))
+}
+
+/// A qXfer read of `offset,len` from `document`: `m` while more follows,
+/// `l` for the last chunk.
+fn xfer_chunk(document: &str, request: &str) -> Option<String> {
+    let mut parts = request.splitn(2, ',');
+    let offset = usize::from_str_radix(parts.next()?, 16).ok()?;
+    let len = usize::from_str_radix(parts.next()?, 16).ok()?;
+    let start = offset.min(document.len());
+    let end = offset.saturating_add(len).min(document.len());
+    let more = if end < document.len() { 'm' } else { 'l' };
+    Some(format!("{}{}", more, &document[start..end]))
+}
+
+/// `addr,len` as used by m, M and Z packets.
+fn parse_addr_len(text: &str) -> Option<(u16, usize)> {
+    let mut parts = text.splitn(2, ',');
+    let addr = parse_hex(parts.next()?)?;
+    let len = usize::from_str_radix(parts.next()?, 16).ok()?;
+    Some((addr, len))
+}
+
+/// Serves the GDB remote serial protocol on top of a `Debugger`: the register
+/// layout, register and memory access, `Z0` breakpoints, `Z2`-`Z4`
+/// watchpoints, step and continue. Memory reads use `peek` and writes bypass
+/// watchpoints, so inspecting the target never changes what it sees.
+pub struct GdbStub<'a, M: Mem> {
+    debugger: &'a mut Debugger<M>,
+}
+
+impl<'a, M: Mem> GdbStub<'a, M> {
+    pub fn new(debugger: &'a mut Debugger<M>) -> Self {
+        GdbStub { debugger: debugger }
+    }
+
+    fn read_registers(&self) -> String {
+        let cpu = &self.debugger.cpu;
+        let pc = cpu.program_counter;
+        hex_bytes(&[
+            cpu.register_a,
+            cpu.register_x,
+            cpu.register_y,
+            cpu.status.bits(),
+            cpu.stack_pointer,
+            pc as u8,
+            (pc >> 8) as u8,
+        ])
+    }
+
+    fn write_register(&mut self, index: usize, bytes: &[u8]) -> Option<()> {
+        let cpu = &mut self.debugger.cpu;
+        match (index, bytes) {
+            (0, [v]) => cpu.register_a = *v,
+            (1, [v]) => cpu.register_x = *v,
+            (2, [v]) => cpu.register_y = *v,
+            (3, [v]) => cpu.status = CpuFlags::from_bits_truncate(*v),
+            (4, [v]) => cpu.stack_pointer = *v,
+            (5, [lo, hi]) => cpu.program_counter = (*hi as u16) << 8 | *lo as u16,
+            _ => return None,
+        }
+        Some(())
+    }
+
+    fn stop_reply(&self, reason: StopReason) -> String {
+        match reason {
+            StopReason::Watchpoint { access, .. } => {
+                let (kind, addr) = match access {
+                    BusAccess::Read { addr, .. } | BusAccess::Fetch { addr, .. } => ("rwatch", addr),
+                    BusAccess::Write { addr, .. } => ("watch", addr),
+                };
+                format!("T{:02x}{}:{:04x};", SIGTRAP, kind, addr)
+            }
+            _ => format!("S{:02x}", SIGTRAP),
+        }
+    }
+
+    fn set_point(&mut self, packet: &str) -> Option<String> {
+        let insert = packet.starts_with('Z');
+        let mut parts = packet[1..].splitn(2, ',');
+        let kind = parts.next()?;
+        let (addr, len) = parse_addr_len(parts.next()?)?;
+        let end = addr.wrapping_add(len.max(1) as u16 - 1);
+
+        let watch = match kind {
+            "0" | "1" => None,
+            "2" => Some(WatchKind::Write),
+            "3" => Some(WatchKind::Read),
+            "4" => Some(WatchKind::Access),
+            _ => return Some(String::new()),
+        };
+        match (watch, insert) {
+            (None, true) => self.debugger.add_breakpoint(addr),
+            (None, false) => {
+                // only the plain breakpoint Z0 added, not conditional ones
+                // set at the same address from the REPL
+                let found = self
+                    .debugger
+                    .breakpoints()
+                    .iter()
+                    .rposition(|bp| bp.addr == Some(addr) && bp.condition.is_none() && !bp.trace);
+                if let Some(index) = found {
+                    self.debugger.delete_breakpoint(index);
+                }
+            }
+            (Some(kind), true) => {
+                self.debugger.add_watchpoint(addr, end, kind);
+            }
+            (Some(kind), false) => {
+                let found = self
+                    .debugger
+                    .watchpoints()
+                    .iter()
+                    .position(|w| w.start == addr && w.end == end && w.kind == kind);
+                if let Some(index) = found {
+                    self.debugger.remove_watchpoint(index);
+                }
+            }
+        }
+        Some(String::from("OK"))
+    }
+
+    /// Handles one packet body and returns the reply body, or None when the
+    /// client is done with the session. `interrupted` is polled while
+    /// continuing so a ^C from the client can stop the target.
+    pub fn handle(&mut self, packet: &str, interrupted: &mut dyn FnMut() -> bool) -> Option<String> {
+        let error = String::from("E01");
+        let reply = match packet.chars().next() {
+            Some('?') => format!("S{:02x}", SIGTRAP),
+            Some('g') => self.read_registers(),
+            Some('G') => match parse_hex_bytes(&packet[1..]) {
+                Some(ref bytes) if bytes.len() == 7 => {
+                    for index in 0..5 {
+                        self.write_register(index, &bytes[index..index + 1]);
+                    }
+                    self.write_register(5, &bytes[5..7]);
+                    String::from("OK")
+                }
+                _ => error,
+            },
+            Some('p') => match usize::from_str_radix(&packet[1..], 16) {
+                Ok(index) if index < REGISTERS.len() => {
+                    let regs = self.read_registers();
+                    let start = index * 2;
+                    let end = if index == 5 { start + 4 } else { start + 2 };
+                    regs[start..end].to_string()
+                }
+                _ => error,
+            },
+            Some('P') => {
+                let mut parts = packet[1..].splitn(2, '=');
+                let index = parts.next().and_then(|i| usize::from_str_radix(i, 16).ok());
+                let bytes = parts.next().and_then(parse_hex_bytes);
+                match (index, bytes) {
+                    (Some(index), Some(bytes)) if self.write_register(index, &bytes).is_some() => {
+                        String::from("OK")
+                    }
+                    _ => error,
+                }
+            }
+            Some('m') => match parse_addr_len(&packet[1..]) {
+                Some((addr, len)) if len <= MAX_READ => {
+                    let bytes: Vec<u8> = (0..len)
+                        .map(|i| self.debugger.cpu.peek(addr.wrapping_add(i as u16)))
+                        .collect();
+                    hex_bytes(&bytes)
+                }
+                _ => error,
+            },
+            Some('M') => {
+                let mut parts = packet[1..].splitn(2, ':');
+                let target = parts.next().and_then(parse_addr_len);
+                let bytes = parts.next().and_then(parse_hex_bytes);
+                match (target, bytes) {
+                    (Some((addr, len)), Some(bytes)) if bytes.len() == len => {
+                        for (i, byte) in bytes.iter().enumerate() {
+                            self.debugger.cpu.bus.mem_write(addr.wrapping_add(i as u16), *byte);
+                        }
+                        String::from("OK")
+                    }
+                    _ => error,
+                }
+            }
+            Some('Z') | Some('z') => self.set_point(packet).unwrap_or(error),
+            Some('s') => {
+                let reason = self.debugger.step();
+                self.stop_reply(reason)
+            }
+            Some('c') => loop {
+                match self.debugger.run(CONTINUE_CHUNK) {
+                    StopReason::Limit => {
+                        if interrupted() {
+                            break format!("S{:02x}", SIGINT);
+                        }
+                    }
+                    reason => break self.stop_reply(reason),
+                }
+            },
+            Some('H') => String::from("OK"),
+            Some('k') => return None,
+            Some('D') => {
+                // the reply has to go out before the connection closes
+                return Some(String::from("OK"));
+            }
+            Some('q') if packet.starts_with("qSupported") => {
+                String::from("PacketSize=1000;qXfer:features:read+")
+            }
+            Some('q') if packet.starts_with("qXfer:features:read:") => {
+                let mut parts = packet["qXfer:features:read:".len()..].splitn(2, ':');
+                match (parts.next(), parts.next()) {
+                    (Some("target.xml"), Some(request)) => xfer_chunk(&target_xml(), request).unwrap_or(error),
+                    _ => String::from("E00"),
+                }
+            }
+            Some('q') if packet.starts_with("qRegisterInfo") => {
+                match usize::from_str_radix(&packet["qRegisterInfo".len()..], 16) {
+                    Ok(index) => register_info(index).unwrap_or_else(|| String::from("E45")),
+                    Err(_) => error,
+                }
+            }
+            Some('q') if packet == "qAttached" => String::from("1"),
+            _ => String::new(),
+        };
+        Some(reply)
+    }
+
+    /// Reads one `$...#xx` packet, acking it. Returns None at end of stream.
+    fn read_packet(stream: &mut TcpStream) -> io::Result<Option<String>> {
+        let mut byte = [0u8; 1];
+        loop {
+            if stream.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            // acks and stray ^Cs between packets are ignored
+            if byte[0] == b'$' {
+                break;
+            }
+        }
+
+        let mut body = vec![];
+        loop {
+            if stream.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            if byte[0] == b'#' {
+                break;
+            }
+            body.push(byte[0]);
+        }
+        let mut sum = [0u8; 2];
+        stream.read_exact(&mut sum)?;
+
+        let body = String::from_utf8_lossy(&body).into_owned();
+        let expected = std::str::from_utf8(&sum).ok().and_then(|s| u8::from_str_radix(s, 16).ok());
+        if expected == Some(checksum(&body)) {
+            stream.write_all(b"+")?;
+            Ok(Some(body))
+        } else {
+            stream.write_all(b"-")?;
+            Self::read_packet(stream)
+        }
+    }
+
+    fn write_packet(stream: &mut TcpStream, body: &str) -> io::Result<()> {
+        write!(stream, "${}#{:02x}", body, checksum(body))?;
+        stream.flush()
+    }
+
+    /// Serves one client until it detaches, kills the session or disconnects.
+    pub fn serve(&mut self, mut stream: TcpStream) -> io::Result<()> {
+        stream.set_nodelay(true)?;
+        while let Some(packet) = Self::read_packet(&mut stream)? {
+            let poll = stream.try_clone()?;
+            let mut interrupted = || {
+                let mut byte = [0u8; 1];
+                let _ = poll.set_nonblocking(true);
+                let got = (&poll).read(&mut byte).map(|n| n == 1 && byte[0] == 0x03);
+                let _ = poll.set_nonblocking(false);
+                got.unwrap_or(false)
+            };
+            match self.handle(&packet, &mut interrupted) {
+                Some(reply) => Self::write_packet(&mut stream, &reply)?,
+                None => break,
+            }
+            if packet.starts_with('D') {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Waits for one gdb connection on `addr` (e.g. "127.0.0.1:6502") and serves it.
+pub fn listen<M: Mem>(debugger: &mut Debugger<M>, addr: &str) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    let (stream, _) = listener.accept()?;
+    GdbStub::new(debugger).serve(stream)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::cpu::{FlatMemory, CPU};
+    use std::thread;
+
+    fn debugger() -> Debugger<FlatMemory> {
+        let program = assemble(
+            "
+                  ldx #$03
+            loop: dex
+                  stx $10
+                  bne loop
+                  brk
+            ",
+            0x0600,
+        )
+        .unwrap();
+        let mut mem = FlatMemory::new();
+        mem.load(0x0600, &program);
+        let mut cpu = CPU::new(mem);
+        cpu.program_counter = 0x0600;
+        Debugger::new(cpu)
+    }
+
+    /// Sends each packet and collects the reply bodies, checking acks and
+    /// checksums the way gdb would.
+    fn client(port: u16, packets: Vec<&'static str>) -> Vec<String> {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let mut replies = vec![];
+        for packet in packets {
+            write!(stream, "${}#{:02x}", packet, checksum(packet)).unwrap();
+            let mut ack = [0u8; 1];
+            stream.read_exact(&mut ack).unwrap();
+            assert_eq!(ack[0], b'+');
+
+            let mut reply = vec![];
+            let mut byte = [0u8; 1];
+            loop {
+                stream.read_exact(&mut byte).unwrap();
+                if byte[0] == b'#' {
+                    break;
+                }
+                reply.push(byte[0]);
+            }
+            let mut sum = [0u8; 2];
+            stream.read_exact(&mut sum).unwrap();
+            stream.write_all(b"+").unwrap();
+
+            let body = String::from_utf8(reply[1..].to_vec()).unwrap();
+            assert_eq!(format!("{:02x}", checksum(&body)), String::from_utf8(sum.to_vec()).unwrap());
+            replies.push(body);
+        }
+        replies
+    }
+
+    #[test]
+    fn test_scripted_session() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let script = thread::spawn(move || {
+            client(
+                port,
+                vec![
+                    "qSupported:multiprocess+",
+                    "?",
+                    "g",
+                    "s",
+                    "p5",
+                    "Z2,10,1",
+                    "c",
+                    "m10,1",
+                    "z2,10,1",
+                    "Z0,607,1",
+                    "c",
+                    "g",
+                    "M10,1:ff",
+                    "m10,1",
+                    "P0=7f",
+                    "p0",
+                    "D",
+                ],
+            )
+        });
+
+        let mut dbg = debugger();
+        let (stream, _) = listener.accept().unwrap();
+        GdbStub::new(&mut dbg).serve(stream).unwrap();
+        let replies = script.join().unwrap();
+
+        assert_eq!(
+            replies,
+            vec![
+                "PacketSize=1000;qXfer:features:read+",
+                "S05",
+                "00000024fd0006",
+                "S05",
+                "0206",
+                "OK",
+                "T05watch:0010;",
+                "02",
+                "OK",
+                "OK",
+                "S05",
+                "00000026fd0706",
+                "OK",
+                "ff",
+                "OK",
+                "7f",
+                "OK",
+            ]
+        );
+        assert_eq!(dbg.cpu.register_a, 0x7f);
+    }
+
+    #[test]
+    fn test_register_layout() {
+        let mut dbg = debugger();
+        let mut stub = GdbStub::new(&mut dbg);
+        let mut never = || false;
+
+        // gdb reads the description in chunks until an `l` reply
+        let mut xml = String::new();
+        loop {
+            let request = format!("qXfer:features:read:target.xml:{:x},40", xml.len());
+            let reply = stub.handle(&request, &mut never).unwrap();
+            xml.push_str(&reply[1..]);
+            if reply.starts_with('l') {
+                break;
+            }
+            assert!(reply.starts_with('m'));
+        }
+        assert_eq!(xml, target_xml());
+        assert!(xml.starts_with("<?xml"));
+        assert!(xml.contains("<reg name=\"sp\" bitsize=\"8\" regnum=\"4\" type=\"uint8\" group=\"general\" generic=\"sp\"/>"));
+        assert!(xml.contains("<reg name=\"pc\" bitsize=\"16\" regnum=\"5\" type=\"code_ptr\""));
+        assert_eq!(stub.handle("qXfer:features:read:other.xml:0,40", &mut never).unwrap(), "E00");
+
+        assert_eq!(
+            stub.handle("qRegisterInfo5", &mut never).unwrap(),
+            "name:pc;bitsize:16;offset:5;encoding:uint;format:hex;set:General Purpose Registers;generic:pc;"
+        );
+        assert_eq!(stub.handle("qRegisterInfo6", &mut never).unwrap(), "E45");
+    }
+
+    #[test]
+    fn test_memory_read_length() {
+        let mut dbg = debugger();
+        let mut stub = GdbStub::new(&mut dbg);
+        let mut never = || false;
+        assert_eq!(stub.handle("m600,10000", &mut never).unwrap().len(), 0x20000);
+        assert_eq!(stub.handle("m600,10001", &mut never).unwrap(), "E01");
+    }
+
+    #[test]
+    fn test_z0_keeps_other_breakpoints() {
+        let mut dbg = debugger();
+        dbg.add_conditional(Some(0x0602), Some("x == 1"), false).unwrap();
+        let mut stub = GdbStub::new(&mut dbg);
+        let mut never = || false;
+        assert_eq!(stub.handle("Z0,602,1", &mut never).unwrap(), "OK");
+        assert_eq!(stub.handle("z0,602,1", &mut never).unwrap(), "OK");
+        assert_eq!(stub.handle("z0,602,1", &mut never).unwrap(), "OK");
+
+        assert_eq!(dbg.breakpoints().len(), 1);
+        assert!(dbg.breakpoints()[0].condition.is_some());
+    }
+}
@@ -0,0 +1,319 @@
+use crate::callstack::Frame;
+use crate::cpu::{Mem, CPU};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// NTSC: 341 dots at three per CPU cycle.
+const CYCLES_PER_SCANLINE: f64 = 341.0 / 3.0;
+
+/// A subroutine or interrupt handler by entry address; None is whatever runs
+/// outside any call, usually the reset code and main loop.
+pub type Routine = Option<u16>;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RoutineStats {
+    pub calls: u64,
+    /// Cycles spent in the routine and everything it called.
+    pub inclusive: u64,
+    /// Cycles spent in the routine's own instructions.
+    pub exclusive: u64,
+    /// Most inclusive cycles the routine took in any one frame.
+    pub peak_inclusive: u64,
+    frame_inclusive: u64,
+}
+
+/// Attributes CPU cycles to the routines on the shadow call stack. Call
+/// `sample` before every instruction, e.g. from `run_with_callback`; the
+/// cycles since the previous sample are charged to the stack as it was then,
+/// so a JSR is billed to its caller and the RTS to the callee.
+pub struct Profiler {
+    stack: Vec<Frame>,
+    last_cycles: usize,
+    last_scanline: u16,
+    started: bool,
+    frames: u64,
+    total: u64,
+    routines: HashMap<Routine, RoutineStats>,
+    /// Exclusive cycles by call path, outermost first, for flame graphs.
+    folded: HashMap<Vec<u16>, u64>,
+    /// Exclusive cycles per routine on each scanline.
+    scanlines: Vec<HashMap<Routine, u64>>,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Profiler {
+            stack: vec![],
+            last_cycles: 0,
+            last_scanline: 0,
+            started: false,
+            frames: 0,
+            total: 0,
+            routines: HashMap::new(),
+            folded: HashMap::new(),
+            scanlines: vec![],
+        }
+    }
+
+    /// Completed frames, counted when the PPU wraps back to scanline 0.
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    pub fn total_cycles(&self) -> u64 {
+        self.total
+    }
+
+    pub fn routine(&self, routine: Routine) -> Option<&RoutineStats> {
+        self.routines.get(&routine)
+    }
+
+    pub fn sample<M: Mem>(&mut self, cpu: &CPU<M>) {
+        let (scanline, _) = cpu.bus.ppu_position();
+        if self.started {
+            let spent = cpu.cycles.saturating_sub(self.last_cycles) as u64;
+            self.charge(spent);
+            if scanline < self.last_scanline {
+                self.end_frame();
+            }
+        }
+
+        let frames = cpu.call_stack.frames();
+        let common = self.stack.iter().zip(frames.iter()).take_while(|(a, b)| a == b).count();
+        for frame in frames[common..].iter() {
+            self.routines.entry(Some(frame.target)).or_default().calls += 1;
+        }
+        self.stack.clear();
+        self.stack.extend_from_slice(frames);
+
+        self.last_cycles = cpu.cycles;
+        self.last_scanline = scanline;
+        self.started = true;
+    }
+
+    fn charge(&mut self, cycles: u64) {
+        if cycles == 0 {
+            return;
+        }
+        self.total += cycles;
+
+        let innermost = self.stack.last().map(|f| f.target);
+        let mut seen: Vec<Routine> = vec![None];
+        for frame in self.stack.iter() {
+            // a recursive routine still only spends the cycles once
+            if !seen.contains(&Some(frame.target)) {
+                seen.push(Some(frame.target));
+            }
+        }
+        for routine in seen {
+            let stats = self.routines.entry(routine).or_default();
+            stats.inclusive += cycles;
+            stats.frame_inclusive += cycles;
+        }
+        self.routines.entry(innermost).or_default().exclusive += cycles;
+
+        let path: Vec<u16> = self.stack.iter().map(|f| f.target).collect();
+        *self.folded.entry(path).or_insert(0) += cycles;
+
+        let line = self.last_scanline as usize;
+        if self.scanlines.len() <= line {
+            self.scanlines.resize(line + 1, HashMap::new());
+        }
+        *self.scanlines[line].entry(innermost).or_insert(0) += cycles;
+    }
+
+    fn end_frame(&mut self) {
+        self.frames += 1;
+        for stats in self.routines.values_mut() {
+            stats.peak_inclusive = stats.peak_inclusive.max(stats.frame_inclusive);
+            stats.frame_inclusive = 0;
+        }
+    }
+
+    /// Averages are per completed frame; a run shorter than a frame counts
+    /// as one.
+    fn per_frame(&self, cycles: u64) -> f64 {
+        cycles as f64 / self.frames.max(1) as f64
+    }
+
+    /// Per-routine table, heaviest inclusive first. `label` names entry
+    /// addresses when symbols are loaded.
+    pub fn report<F>(&self, label: F) -> String
+    where
+        F: Fn(u16) -> Option<String>,
+    {
+        let mut lines = vec![format!(
+            "{} frames, {} cycles ({:.1} per frame)",
+            self.frames,
+            self.total,
+            self.per_frame(self.total)
+        )];
+        lines.push(format!(
+            "{:<24} {:>8} {:>12} {:>12} {:>10} {:>7}",
+            "routine", "calls", "incl/frame", "excl/frame", "peak incl", "incl%"
+        ));
+
+        let mut routines: Vec<(&Routine, &RoutineStats)> = self.routines.iter().collect();
+        routines.sort_by(|a, b| b.1.inclusive.cmp(&a.1.inclusive).then(a.0.cmp(b.0)));
+        for (routine, stats) in routines {
+            lines.push(format!(
+                "{:<24} {:>8} {:>12.1} {:>12.1} {:>10} {:>6.1}%",
+                name(*routine, &label),
+                stats.calls,
+                self.per_frame(stats.inclusive),
+                self.per_frame(stats.exclusive),
+                stats.peak_inclusive.max(stats.frame_inclusive),
+                percent(stats.inclusive, self.total)
+            ));
+        }
+        lines.join("\n")
+    }
+
+    /// CPU cycles per frame spent on each scanline, with the routine that
+    /// took most of them. A line can exceed 100% when an instruction that
+    /// started on it ran into the next one.
+    pub fn scanline_report<F>(&self, label: F) -> String
+    where
+        F: Fn(u16) -> Option<String>,
+    {
+        let mut lines = vec![format!(
+            "{:<8} {:>12} {:>6}  {}",
+            "scanline", "cycles/frame", "usage", "busiest routine"
+        )];
+        for (line, routines) in self.scanlines.iter().enumerate() {
+            let cycles: u64 = routines.values().sum();
+            if cycles == 0 {
+                continue;
+            }
+            let (top, top_cycles) = routines
+                .iter()
+                .max_by(|a, b| a.1.cmp(b.1).then(b.0.cmp(a.0)))
+                .map(|(r, c)| (*r, *c))
+                .unwrap();
+            let per_frame = self.per_frame(cycles);
+            lines.push(format!(
+                "{:<8} {:>12.1} {:>5.1}%  {} ({:.0}%)",
+                line,
+                per_frame,
+                per_frame * 100.0 / CYCLES_PER_SCANLINE,
+                name(top, &label),
+                percent(top_cycles, cycles)
+            ));
+        }
+        lines.join("\n")
+    }
+
+    /// Brendan Gregg's folded stack format, `root;caller;callee cycles` per
+    /// line, for flamegraph.pl, inferno or speedscope.
+    pub fn folded<F>(&self, label: F) -> String
+    where
+        F: Fn(u16) -> Option<String>,
+    {
+        let mut lines: Vec<String> = self
+            .folded
+            .iter()
+            .map(|(path, cycles)| {
+                let mut names = vec![name(None, &label)];
+                names.extend(path.iter().map(|addr| name(Some(*addr), &label)));
+                format!("{} {}", names.join(";"), cycles)
+            })
+            .collect();
+        lines.sort();
+        lines.join("\n") + "\n"
+    }
+
+    pub fn save_folded<P, F>(&self, path: P, label: F) -> io::Result<()>
+    where
+        P: AsRef<Path>,
+        F: Fn(u16) -> Option<String>,
+    {
+        fs::write(path, self.folded(label))
+    }
+}
+
+fn name<F>(routine: Routine, label: &F) -> String
+where
+    F: Fn(u16) -> Option<String>,
+{
+    match routine {
+        Some(addr) => label(addr).unwrap_or_else(|| format!("${:04X}", addr)),
+        None => String::from("(root)"),
+    }
+}
+
+fn percent(part: u64, whole: u64) -> f64 {
+    if whole == 0 {
+        0.0
+    } else {
+        part as f64 * 100.0 / whole as f64
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::cpu::FlatMemory;
+
+    fn profile(steps: usize) -> Profiler {
+        let program = assemble(
+            "jsr outer\n\
+             jsr inner\n\
+             done: jmp done\n\
+             outer: jsr inner\n\
+             rts\n\
+             inner: nop\n\
+             rts\n",
+            0x0600,
+        )
+        .unwrap();
+        let mut memory = FlatMemory::new();
+        memory.load(0x0600, &program);
+        let mut cpu = CPU::new(memory);
+        cpu.program_counter = 0x0600;
+
+        let mut profiler = Profiler::new();
+        for _ in 0..steps {
+            profiler.sample(&cpu);
+            cpu.step();
+        }
+        profiler.sample(&cpu);
+        profiler
+    }
+
+    #[test]
+    fn test_inclusive_and_exclusive() {
+        // four trips round the `done` loop after the calls return
+        let profiler = profile(12);
+        let outer = profiler.routine(Some(0x0609)).unwrap();
+        let inner = profiler.routine(Some(0x060d)).unwrap();
+        let root = profiler.routine(None).unwrap();
+
+        assert_eq!((inner.calls, inner.exclusive, inner.inclusive), (2, 16, 16));
+        assert_eq!((outer.calls, outer.exclusive, outer.inclusive), (1, 12, 20));
+        assert_eq!((root.exclusive, root.inclusive), (24, 52));
+        assert_eq!(profiler.total_cycles(), 52);
+        // FlatMemory never leaves scanline 0
+        assert_eq!(profiler.frames(), 0);
+        assert!(profiler.scanline_report(|_| None).contains("(root) (46%)"));
+    }
+
+    #[test]
+    fn test_folded_stacks() {
+        let profiler = profile(12);
+        let label = |addr: u16| match addr {
+            0x0609 => Some(String::from("outer")),
+            0x060d => Some(String::from("inner")),
+            _ => None,
+        };
+        assert_eq!(
+            profiler.folded(label),
+            "(root) 24\n(root);inner 8\n(root);outer 12\n(root);outer;inner 8\n"
+        );
+        let report = profiler.report(label);
+        assert!(report.lines().nth(2).unwrap().starts_with("(root)"));
+        assert!(report.contains("outer"));
+    }
+}
@@ -0,0 +1,312 @@
+use crate::cpu::Mem;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// iNES header in front of PRG ROM in the files ld65 writes.
+const INES_HEADER: usize = 16;
+/// FCEUX numbers its .nl files by 16 KiB PRG bank.
+const NL_BANK_SIZE: usize = 0x4000;
+
+/// Where a label lives. Labels in cartridge ROM are keyed by PRG ROM offset,
+/// so the right one shows up whichever bank the mapper has switched in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Location {
+    Cpu(u16),
+    PrgRom(usize),
+}
+
+/// Labels loaded from ca65 debug info, FCEUX .nl files or Mesen .mlb files.
+pub struct Symbols {
+    labels: HashMap<Location, String>,
+    by_name: HashMap<String, Location>,
+}
+
+impl Symbols {
+    pub fn new() -> Self {
+        Symbols {
+            labels: HashMap::new(),
+            by_name: HashMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.labels.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty()
+    }
+
+    /// The first label seen for a location wins, except that ca65 cheap
+    /// locals (`@loop`) give way to a proper name.
+    pub fn insert(&mut self, location: Location, name: &str) {
+        let name = name.trim();
+        if name.is_empty() {
+            return;
+        }
+        let replace = match self.labels.get(&location) {
+            Some(existing) => existing.starts_with('@') && !name.starts_with('@'),
+            None => true,
+        };
+        if replace {
+            self.labels.insert(location, name.to_string());
+        }
+        self.by_name.entry(name.to_string()).or_insert(location);
+    }
+
+    /// Label for CPU address `addr` with the banks currently mapped on `mem`.
+    pub fn label<M: Mem>(&self, mem: &M, addr: u16) -> Option<&str> {
+        if let Some(offset) = mem.prg_rom_offset(addr) {
+            if let Some(name) = self.labels.get(&Location::PrgRom(offset)) {
+                return Some(name);
+            }
+        }
+        self.labels.get(&Location::Cpu(addr)).map(|s| s.as_str())
+    }
+
+    /// CPU address of `name`. ROM labels only resolve while their bank is
+    /// mapped, so this searches the cartridge window for the offset.
+    pub fn address<M: Mem>(&self, mem: &M, name: &str) -> Option<u16> {
+        match *self.by_name.get(name)? {
+            Location::Cpu(addr) => Some(addr),
+            Location::PrgRom(offset) => {
+                (0x8000..=0xffffu16).find(|addr| mem.prg_rom_offset(*addr) == Some(offset))
+            }
+        }
+    }
+
+    /// FCEUX label file: one `$ADDR#Name#Comment` per line. `bank` is the
+    /// 16 KiB PRG bank the file describes, or None for the `.ram.nl` file.
+    pub fn load_nl(&mut self, text: &str, bank: Option<usize>) {
+        for line in text.lines() {
+            let mut fields = line.splitn(3, '#');
+            let addr = fields.next().and_then(|a| u16::from_str_radix(a.trim().trim_start_matches('$'), 16).ok());
+            let name = fields.next();
+            let (addr, name) = match (addr, name) {
+                (Some(addr), Some(name)) => (addr, name),
+                _ => continue,
+            };
+            let location = match bank {
+                Some(bank) if addr >= 0x8000 => {
+                    Location::PrgRom(bank * NL_BANK_SIZE + (addr as usize & (NL_BANK_SIZE - 1)))
+                }
+                _ => Location::Cpu(addr),
+            };
+            self.insert(location, name);
+        }
+    }
+
+    /// Mesen label file: `Type:Addr[-End]:Name[:Comment]`, with either the
+    /// Mesen 1 one-letter types or the Mesen 2 long ones.
+    pub fn load_mlb(&mut self, text: &str) {
+        for line in text.lines() {
+            let fields: Vec<&str> = line.splitn(4, ':').collect();
+            if fields.len() < 3 {
+                continue;
+            }
+            let start = fields[1].split('-').next().unwrap_or("");
+            let addr = match usize::from_str_radix(start, 16) {
+                Ok(addr) => addr,
+                Err(_) => continue,
+            };
+            let location = match fields[0] {
+                "P" | "NesPrgRom" => Location::PrgRom(addr),
+                "R" | "G" | "NesInternalRam" | "NesMemory" => Location::Cpu(addr as u16),
+                // save and work RAM both sit at $6000 on NROM-style boards
+                "S" | "W" | "NesSaveRam" | "NesWorkRam" => Location::Cpu(0x6000 + addr as u16),
+                _ => continue,
+            };
+            self.insert(location, fields[2]);
+        }
+    }
+
+    /// ld65 `--dbgfile` output. Labels in segments written to the ROM file
+    /// are keyed by PRG offset (from the segment's `ooffs`); RAM segments and
+    /// segments with no output stay CPU addresses. Equates are skipped, as
+    /// most of them are constants rather than addresses.
+    pub fn load_dbg(&mut self, text: &str) {
+        // segment id -> (start address, PRG offset of start)
+        let mut segments: HashMap<String, (usize, Option<usize>)> = HashMap::new();
+        let mut syms = vec![];
+
+        for line in text.lines() {
+            let mut parts = line.splitn(2, '\t');
+            let kind = parts.next().unwrap_or("");
+            let attrs = parse_dbg_attrs(parts.next().unwrap_or(""));
+            let number = |key: &str| attrs.get(key).and_then(|v| parse_dbg_number(v));
+
+            match kind {
+                "seg" => {
+                    let id = attrs.get("id").cloned().unwrap_or_default();
+                    let start = number("start").unwrap_or(0);
+                    let prg = number("ooffs").filter(|o| *o >= INES_HEADER).map(|o| o - INES_HEADER);
+                    segments.insert(id, (start, prg));
+                }
+                "sym" if attrs.get("type").map(|t| t.as_str()) == Some("lab") => {
+                    if let (Some(name), Some(val)) = (attrs.get("name"), number("val")) {
+                        syms.push((name.clone(), val, attrs.get("seg").cloned()));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        for (name, val, seg) in syms {
+            let location = match seg.and_then(|s| segments.get(&s).cloned()) {
+                Some((start, Some(prg))) if val >= 0x8000 => Location::PrgRom(prg + val - start),
+                _ => Location::Cpu(val as u16),
+            };
+            self.insert(location, &name);
+        }
+    }
+
+    /// Loads a file by extension: `.dbg`, `.mlb`, or `.nl`. For .nl the bank
+    /// comes from the name, e.g. `game.nes.1.nl`; any other .nl, such as
+    /// `game.nes.ram.nl`, holds RAM and unbanked labels.
+    pub fn load_file<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)?;
+        let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+
+        if name.ends_with(".dbg") {
+            self.load_dbg(&text);
+        } else if name.ends_with(".mlb") {
+            self.load_mlb(&text);
+        } else if name.ends_with(".nl") {
+            self.load_nl(&text, nl_bank(name));
+        } else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("don't know how to load symbols from {}", name),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// The hex bank in an FCEUX `<rom>.nes.<bank>.nl` file name.
+fn nl_bank(name: &str) -> Option<usize> {
+    let stem = name.strip_suffix(".nl")?;
+    let (rom, bank) = stem.rsplit_once('.')?;
+    if !rom.to_ascii_lowercase().ends_with(".nes") || bank.is_empty() {
+        return None;
+    }
+    usize::from_str_radix(bank, 16).ok()
+}
+
+/// `id=0,name="main",val=0xC000` into a map, unquoting strings.
+fn parse_dbg_attrs(text: &str) -> HashMap<String, String> {
+    let mut attrs = HashMap::new();
+    let mut rest = text;
+    while !rest.is_empty() {
+        let eq = match rest.find('=') {
+            Some(eq) => eq,
+            None => break,
+        };
+        let key = rest[..eq].to_string();
+        rest = &rest[eq + 1..];
+
+        let value = if rest.starts_with('"') {
+            let close = rest[1..].find('"').map(|i| i + 1).unwrap_or(rest.len());
+            let value = rest[1..close].to_string();
+            rest = &rest[(close + 1).min(rest.len())..];
+            value
+        } else {
+            let end = rest.find(',').unwrap_or(rest.len());
+            let value = rest[..end].to_string();
+            rest = &rest[end..];
+            value
+        };
+        attrs.insert(key, value);
+        rest = rest.trim_start_matches(',');
+    }
+    attrs
+}
+
+fn parse_dbg_number(text: &str) -> Option<usize> {
+    match text.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::FlatMemory;
+
+    /// 32 KiB of PRG at $8000 with a switchable lower 16 KiB, like UNROM.
+    struct Banked {
+        memory: FlatMemory,
+        bank: usize,
+    }
+
+    impl Mem for Banked {
+        fn mem_read(&mut self, addr: u16) -> u8 {
+            self.memory.mem_read(addr)
+        }
+        fn mem_write(&mut self, addr: u16, data: u8) {
+            self.memory.mem_write(addr, data)
+        }
+        fn peek(&self, addr: u16) -> u8 {
+            self.memory.peek(addr)
+        }
+        fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+            match addr {
+                0x8000..=0xbfff => Some(self.bank * 0x4000 + (addr as usize - 0x8000)),
+                0xc000..=0xffff => Some(7 * 0x4000 + (addr as usize - 0xc000)),
+                _ => None,
+            }
+        }
+    }
+
+    #[test]
+    fn test_nl_and_mlb_with_banks() {
+        let mut symbols = Symbols::new();
+        symbols.load_nl("$8000#Bank0Entry#\n$C3A0#UpdateSprites#moves OAM\n", Some(0));
+        symbols.load_nl("$8000#Bank1Entry#\n", Some(1));
+        symbols.load_nl("$0010#scroll_x#\n", None);
+        symbols.load_mlb("R:0300:oam_buffer\nP:1C3A0:Reset:entry point\nG:2000:PPUCTRL\n");
+
+        let mut mem = Banked { memory: FlatMemory::new(), bank: 0 };
+        assert_eq!(symbols.label(&mem, 0x8000), Some("Bank0Entry"));
+        mem.bank = 1;
+        assert_eq!(symbols.label(&mem, 0x8000), Some("Bank1Entry"));
+        assert_eq!(symbols.label(&mem, 0xc3a0), Some("Reset"));
+        assert_eq!(symbols.label(&mem, 0x0010), Some("scroll_x"));
+        assert_eq!(symbols.label(&mem, 0x2000), Some("PPUCTRL"));
+        assert_eq!(symbols.address(&mem, "Reset"), Some(0xc3a0));
+        assert_eq!(symbols.address(&mem, "Bank0Entry"), None);
+    }
+
+    #[test]
+    fn test_nl_bank_from_file_name() {
+        assert_eq!(nl_bank("game.nes.1.nl"), Some(1));
+        assert_eq!(nl_bank("Game.NES.1f.nl"), Some(0x1f));
+        assert_eq!(nl_bank("game.nes.ram.nl"), None);
+        assert_eq!(nl_bank("game.bad.nl"), None);
+        assert_eq!(nl_bank("game.nes.nl"), None);
+        assert_eq!(nl_bank("game.nl"), None);
+    }
+
+    #[test]
+    fn test_ca65_dbg() {
+        let dbg = "version\tmajor=2,minor=0\n\
+            seg\tid=0,name=\"CODE\",start=0x00C000,size=0x0400,addrsize=absolute,type=ro,oname=\"game.nes\",ooffs=16400\n\
+            seg\tid=1,name=\"BSS\",start=0x000300,size=0x0100,addrsize=absolute,type=rw\n\
+            sym\tid=0,name=\"UpdateSprites\",addrsize=absolute,scope=0,def=1,val=0xC3A0,seg=0,type=lab\n\
+            sym\tid=1,name=\"@loop\",addrsize=absolute,scope=1,def=2,val=0xC3A0,seg=0,type=lab\n\
+            sym\tid=2,name=\"oam\",addrsize=absolute,scope=0,def=3,val=0x300,seg=1,type=lab\n\
+            sym\tid=3,name=\"SPEED\",addrsize=zeropage,scope=0,def=4,val=0x3,type=equ\n";
+        let mut symbols = Symbols::new();
+        symbols.load_dbg(dbg);
+
+        assert_eq!(symbols.len(), 2);
+        // CODE starts 0x4000 bytes into PRG, so $C3A0 is offset $43A0
+        assert_eq!(symbols.labels.get(&Location::PrgRom(0x43a0)).map(|s| s.as_str()), Some("UpdateSprites"));
+        assert_eq!(symbols.label(&FlatMemory::new(), 0x0300), Some("oam"));
+        assert_eq!(symbols.label(&FlatMemory::new(), 0x0003), None);
+    }
+}
@@ -1,19 +1,102 @@
 use crate::cartridge::Mirroring;
-use registers::addr::AddrRegister;
+use crate::cdl::{ChrFlags, CodeDataLog};
+use crate::loopy::LoopyRegister;
+use std::cell::RefCell;
+use std::rc::Rc;
 use registers::control::ControlRegister;
+use registers::mask::MaskRegister;
 use registers::status::StatusRegister;
 
 pub mod registers;
 
+pub const SCREEN_WIDTH: usize = 256;
+pub const SCREEN_HEIGHT: usize = 240;
+
+const DOTS_PER_SCANLINE: usize = 341;
+const SCANLINES_PER_FRAME: u16 = 262;
+const VBLANK_SCANLINE: u16 = 241;
+const PRE_RENDER_SCANLINE: u16 = 261;
+const SPRITES_PER_LINE: usize = 8;
+
+/// Background fetch state: the tile being fetched and the shifters
+/// holding the two tiles being drawn.
+struct Background {
+    next_tile: u8,
+    next_attribute: u8,
+    next_pattern_lo: u8,
+    next_pattern_hi: u8,
+
+    pattern_lo: u16,
+    pattern_hi: u16,
+    attribute_lo: u16,
+    attribute_hi: u16,
+}
+
+impl Background {
+    fn new() -> Self {
+        Background {
+            next_tile: 0,
+            next_attribute: 0,
+            next_pattern_lo: 0,
+            next_pattern_hi: 0,
+            pattern_lo: 0,
+            pattern_hi: 0,
+            attribute_lo: 0,
+            attribute_hi: 0,
+        }
+    }
+
+    fn shift(&mut self) {
+        self.pattern_lo <<= 1;
+        self.pattern_hi <<= 1;
+        self.attribute_lo <<= 1;
+        self.attribute_hi <<= 1;
+    }
+
+    /// Moves the fetched tile into the low half of the shifters; the high
+    /// half is the tile being drawn.
+    fn reload(&mut self) {
+        self.pattern_lo = (self.pattern_lo & 0xff00) | self.next_pattern_lo as u16;
+        self.pattern_hi = (self.pattern_hi & 0xff00) | self.next_pattern_hi as u16;
+        let lo = if self.next_attribute & 0b01 != 0 { 0xff } else { 0x00 };
+        let hi = if self.next_attribute & 0b10 != 0 { 0xff } else { 0x00 };
+        self.attribute_lo = (self.attribute_lo & 0xff00) | lo;
+        self.attribute_hi = (self.attribute_hi & 0xff00) | hi;
+    }
+
+    /// (pixel 0-3, palette 0-3) at the current dot.
+    fn pixel(&self, fine_x: u8) -> (u8, u8) {
+        let bit = 0x8000 >> fine_x;
+        let value = ((self.pattern_hi & bit != 0) as u8) << 1 | (self.pattern_lo & bit != 0) as u8;
+        let palette = ((self.attribute_hi & bit != 0) as u8) << 1 | (self.attribute_lo & bit != 0) as u8;
+        (value, palette)
+    }
+}
+
+/// A sprite fetched for the line being drawn, pattern already flipped so
+/// bit 7 is its leftmost pixel.
+#[derive(Debug, Clone, Copy)]
+struct LineSprite {
+    /// OAM sprite 0, the one that can set the sprite-0 hit flag.
+    zero: bool,
+    x: u8,
+    attributes: u8,
+    pattern_lo: u8,
+    pattern_hi: u8,
+}
+
 pub struct NesPPU {
     pub chr_rom: Vec<u8>,
     pub mirroring: Mirroring,
     pub ctrl: ControlRegister,
+    pub mask: MaskRegister,
     pub status: StatusRegister,
-    pub addr: AddrRegister,
+    /// v, t, fine X and the write toggle shared by $2005 and $2006.
+    pub loopy: LoopyRegister,
     pub vram: [u8; 2048],
 
     pub oam_addr: u8,
+    pub oam_data: [u8; 256],
     pub palette_table: [u8; 32],
 
     internal_data_buf: u8,
@@ -21,6 +104,24 @@ pub struct NesPPU {
     pub scanline: u16,
     cycles: usize,
     pub nmi_interrupt: Option<u8>,
+    /// Set by `Bus::start_cdl`; shared so rendering can log through `&NesPPU`.
+    pub cdl: Option<Rc<RefCell<CodeDataLog>>>,
+
+    /// Every pixel as `emphasis << 6 | colour`: the PPUMASK emphasis bits
+    /// over a system palette index, one written per visible dot.
+    pub pixels: Vec<u16>,
+    background: Background,
+    /// Sprites found on the next line, in OAM order.
+    secondary_oam: [u8; 32],
+    sprite_count: usize,
+    sprite_zero_next: bool,
+    /// In-range sprites past the eighth, kept only in unlimited mode.
+    extra_sprites: Vec<[u8; 4]>,
+    line_sprites: Vec<LineSprite>,
+    /// Draws every sprite on a line instead of the first eight. Removes the
+    /// flicker games use to cycle through them; the overflow flag still
+    /// behaves as on hardware.
+    pub unlimited_sprites: bool,
 }
 
 pub trait PPU
@@ -50,15 +151,34 @@ impl NesPPU {
         {
             chr_rom: chr_rom,
             mirroring: mirroring,
+            ctrl: ControlRegister::new(),
             mask: MaskRegister::new(),
             status: StatusRegister::new(),
             oam_addr: 0,
-            scroll: ScrollRegister::new(),
-            addr: AddrRegister::new(),
+            loopy: LoopyRegister::new(),
             vram: [0; 2048],
             oam_data: [0; 64 * 4],
             palette_table: [0; 32],
             internal_data_buf: 0,
+            scanline: 0,
+            cycles: 0,
+            nmi_interrupt: None,
+            cdl: None,
+            pixels: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT],
+            background: Background::new(),
+            secondary_oam: [0xff; 32],
+            sprite_count: 0,
+            sprite_zero_next: false,
+            extra_sprites: vec![],
+            line_sprites: vec![],
+            unlimited_sprites: false,
+        }
+    }
+
+    /// Marks CHR bytes in the code/data log, if one is running.
+    pub fn log_chr(&self, offset: usize, len: usize, flags: ChrFlags) {
+        if let Some(cdl) = &self.cdl {
+            cdl.borrow_mut().log_chr(offset, len, flags);
         }
     }
 
@@ -76,46 +196,331 @@ impl NesPPU {
     }
 
     fn increment_vram_addr(&mut self) {
-        self.addr.increment(self.ctrl.vram_addr_increment());
+        self.loopy.increment(self.ctrl.vram_addr_increment());
+    }
+
+    /// Fetches and scroll updates only run with the background or sprites
+    /// switched on; otherwise `v` is left alone for $2007.
+    pub fn rendering_enabled(&self) -> bool {
+        self.mask.show_background() || self.mask.show_sprites()
     }
 
+    /// Advances the PPU by `cycles` dots. Returns true when a frame has
+    /// finished.
     pub fn tick(&mut self, cycles: u8) -> bool {
-        self.cycles += cycles as usize;
-        if self.cycles >= 341 {
-            if self.is_sprite_0_hit(self.cycles) {
-                self.status.set_sprite_zero_hit(true);
+        let mut frame_done = false;
+        for _ in 0..cycles {
+            frame_done |= self.dot();
+        }
+        frame_done
+    }
+
+    fn dot(&mut self) -> bool {
+        if self.scanline == PRE_RENDER_SCANLINE && self.cycles == 1 {
+            self.status.reset_vblank_status();
+            self.status.set_sprite_zero_hit(false);
+            self.status.set_sprite_overflow(false);
+        }
+
+        if self.scanline < SCREEN_HEIGHT as u16 || self.scanline == PRE_RENDER_SCANLINE {
+            self.render_dot();
+        }
+
+        self.cycles += 1;
+        if self.cycles < DOTS_PER_SCANLINE {
+            return false;
+        }
+
+        self.cycles = 0;
+        self.scanline += 1;
+
+        if self.scanline == VBLANK_SCANLINE {
+            self.status.set_vblank_status(true);
+            if self.ctrl.generate_vblank_nmi() {
+                self.nmi_interrupt = Some(1);
             }
+        }
+
+        if self.scanline >= SCANLINES_PER_FRAME {
+            self.scanline = 0;
+            self.nmi_interrupt = None;
+            return true;
+        }
+        false
+    }
 
-            self.cycles = self.cycles - 341;
-            self.scanline += 1;
+    /// One dot of a visible or pre-render line. Background fetches follow the
+    /// hardware's 8-dot pattern (nametable, attribute, pattern low, pattern
+    /// high) for dots 1-256 and the first two tiles of the next line at
+    /// 321-336; the shifters feed one pixel per dot. Scrolling is entirely
+    /// through `v`: coarse X steps after each tile, Y at dot 256, and `t` is
+    /// copied back in at dot 257 and during the pre-render line.
+    fn render_dot(&mut self) {
+        let dot = self.cycles;
 
-            if self.scanline == 241 {
-                self.status.set_vblank_status(true);
-                self.status.set_sprite_zero_hit(false);
-                if self.ctrl.generate_vblank_nmi() {
-                    self.nmi_interrupt = Some(1);
+        if self.rendering_enabled() {
+            if (dot >= 2 && dot <= 257) || (dot >= 321 && dot <= 337) {
+                self.background.shift();
+                match (dot - 1) % 8 {
+                    0 => {
+                        self.background.reload();
+                        self.fetch_nametable_byte();
+                    }
+                    2 => self.fetch_attribute_byte(),
+                    4 => self.background.next_pattern_lo = self.fetch_pattern_byte(0),
+                    6 => self.background.next_pattern_hi = self.fetch_pattern_byte(8),
+                    7 => self.loopy.increment_x(),
+                    _ => {}
                 }
             }
+            if dot == 256 {
+                self.loopy.increment_y();
+            }
+            if dot == 257 {
+                self.loopy.copy_x();
+            }
+            if self.scanline == PRE_RENDER_SCANLINE && dot >= 280 && dot <= 304 {
+                self.loopy.copy_y();
+            }
+        }
 
-            if self.scanline >= 262 {
-                self.scanline = 0;
-                self.nmi_interrupt = None;
-                self.status.set_sprite_zero_hit(false);
-                self.status.reset_vblank_status();
-                return true;
+        // taken after the shift: dot 1 draws X 0, dot 2 X 1 and so on
+        if self.scanline < SCREEN_HEIGHT as u16 && dot >= 1 && dot <= SCREEN_WIDTH {
+            self.output_pixel(dot - 1);
+        }
+
+        if dot == 257 {
+            if self.scanline < SCREEN_HEIGHT as u16 && self.rendering_enabled() {
+                self.evaluate_sprites();
+                self.fetch_sprites();
+            } else {
+                self.line_sprites.clear();
             }
         }
-        return false;
     }
 
-    pub fn poll_nmi_interrupt(&mut self) -> Option<u8> {
-        self.nmi_interrupt.take()
+    fn fetch_nametable_byte(&mut self) {
+        self.background.next_tile = self.read_vram(self.loopy.tile_addr());
+    }
+
+    fn fetch_attribute_byte(&mut self) {
+        let attribute = self.read_vram(self.loopy.attribute_addr());
+        self.background.next_attribute = (attribute >> self.loopy.attribute_shift()) & 0b11;
+    }
+
+    /// One bitplane of the current row of the fetched tile: `plane` is 0 for
+    /// the low plane and 8 for the high one.
+    fn fetch_pattern_byte(&self, plane: u16) -> u8 {
+        let addr = self.ctrl.bknd_pattern_addr()
+            + self.background.next_tile as u16 * 16
+            + self.loopy.fine_y()
+            + plane;
+        self.log_chr(addr as usize, 1, ChrFlags::BACKGROUND);
+        self.read_vram(addr)
+    }
+
+    /// Sprite evaluation for the next line, done in one go at dot 257 rather
+    /// than spread over dots 65-256; `fetch_sprites` then fetches the
+    /// patterns in the same step instead of over dots 257-320. OAM Y is one
+    /// less than the first line a sprite appears on. The first eight in
+    /// range go into secondary OAM; after that the overflow check reproduces
+    /// the hardware bug of stepping the byte index along with the sprite
+    /// index, so tile, attribute and X bytes get compared as Y coordinates.
+    fn evaluate_sprites(&mut self) {
+        let line = self.scanline;
+        let height = self.ctrl.sprite_size() as u16;
+        let in_range = |y: u8| line.wrapping_sub(y as u16) < height;
+
+        self.secondary_oam = [0xff; 32];
+        self.sprite_count = 0;
+        self.sprite_zero_next = false;
+        self.extra_sprites.clear();
+
+        let mut n = 0;
+        while n < 64 && self.sprite_count < SPRITES_PER_LINE {
+            if in_range(self.oam_data[n * 4]) {
+                let slot = self.sprite_count * 4;
+                self.secondary_oam[slot..slot + 4].copy_from_slice(&self.oam_data[n * 4..n * 4 + 4]);
+                self.sprite_zero_next |= n == 0;
+                self.sprite_count += 1;
+            }
+            n += 1;
+        }
+
+        if self.unlimited_sprites {
+            for sprite in self.oam_data[n * 4..].chunks(4) {
+                if in_range(sprite[0]) {
+                    self.extra_sprites.push([sprite[0], sprite[1], sprite[2], sprite[3]]);
+                }
+            }
+        }
+
+        let mut m = 0;
+        while n < 64 {
+            if in_range(self.oam_data[n * 4 + m]) {
+                self.status.set_sprite_overflow(true);
+                break;
+            }
+            n += 1;
+            m = (m + 1) & 0b11;
+        }
+    }
+
+    /// Fetches patterns for the sprites evaluated onto the next line. 8x16
+    /// sprites take their pattern table from bit 0 of the tile number and
+    /// use the even tile on top; a vertical flip swaps the two halves too.
+    fn fetch_sprites(&mut self) {
+        self.line_sprites.clear();
+        let line = self.scanline;
+        let height = self.ctrl.sprite_size() as u16;
+        let mut sprites: Vec<[u8; 4]> = self.secondary_oam[..self.sprite_count * 4]
+            .chunks(4)
+            .map(|s| [s[0], s[1], s[2], s[3]])
+            .collect();
+        sprites.extend(self.extra_sprites.iter().cloned());
+
+        for (i, sprite) in sprites.iter().enumerate() {
+            let row = line.wrapping_sub(sprite[0] as u16);
+            let attributes = sprite[2];
+            let row = if attributes & 0x80 != 0 { height - 1 - row } else { row };
+            let (bank, tile) = if height == 16 {
+                ((sprite[1] as u16 & 1) * 0x1000, (sprite[1] & 0xfe) as u16 + row / 8)
+            } else {
+                (self.ctrl.sprt_pattern_addr(), sprite[1] as u16)
+            };
+            let addr = bank + tile * 16 + row % 8;
+            self.log_chr(addr as usize, 1, ChrFlags::SPRITE);
+            self.log_chr(addr as usize + 8, 1, ChrFlags::SPRITE);
+
+            let mut pattern_lo = self.read_vram(addr);
+            let mut pattern_hi = self.read_vram(addr + 8);
+            if attributes & 0x40 != 0 {
+                pattern_lo = pattern_lo.reverse_bits();
+                pattern_hi = pattern_hi.reverse_bits();
+            }
+            self.line_sprites.push(LineSprite {
+                zero: i == 0 && self.sprite_zero_next,
+                x: sprite[3],
+                attributes: attributes,
+                pattern_lo: pattern_lo,
+                pattern_hi: pattern_hi,
+            });
+        }
+    }
+
+    /// The first opaque sprite at `x`; lower OAM indexes win.
+    fn sprite_pixel(&self, x: usize) -> Option<(u8, &LineSprite)> {
+        self.line_sprites.iter().find_map(|sprite| {
+            let column = x.wrapping_sub(sprite.x as usize);
+            if column >= 8 {
+                return None;
+            }
+            let bit = 7 - column;
+            let value = ((sprite.pattern_hi >> bit) & 1) << 1 | ((sprite.pattern_lo >> bit) & 1);
+            if value == 0 {
+                None
+            } else {
+                Some((value, sprite))
+            }
+        })
+    }
+
+    fn output_pixel(&mut self, x: usize) {
+        let colour = if self.rendering_enabled() {
+            self.composite_pixel(x)
+        } else {
+            self.backdrop()
+        };
+        let colour = if self.mask.is_grayscale() { colour & 0x30 } else { colour & 0x3f };
+        let emphasis = (self.mask.bits() >> 5) as u16;
+        self.pixels[self.scanline as usize * SCREEN_WIDTH + x] = emphasis << 6 | colour as u16;
+    }
+
+    /// With rendering off the PPU shows the backdrop colour, or the palette
+    /// entry `v` points at when it is in palette RAM.
+    fn backdrop(&self) -> u8 {
+        match self.loopy.addr() {
+            addr @ 0x3f00..=0x3fff => self.palette_table[(addr as usize - 0x3f00) & 0x1f],
+            _ => self.palette_table[0],
+        }
     }
 
-    fn is_sprite_0_hit(&self, cycle: usize) -> bool {
-        let y = self.oam_data[0] as usize;
-        let x = self.oam_data[3] as usize;
-        (y == self.scanline as usize) && x <= cycle && self.mask.show_sprites()
+    /// Background and sprite pixel at `x` after PPUMASK has switched layers
+    /// off or clipped them out of the leftmost 8 pixels.
+    fn composite_pixel(&mut self, x: usize) -> u8 {
+        let show_background = self.mask.show_background() && (x >= 8 || self.mask.leftmost_8pxl_background());
+        let show_sprites = self.mask.show_sprites() && (x >= 8 || self.mask.leftmost_8pxl_sprite());
+
+        let (bg_value, bg_palette) = if show_background {
+            self.background.pixel(self.loopy.x)
+        } else {
+            (0, 0)
+        };
+        let sprite = if show_sprites {
+            self.sprite_pixel(x).map(|(value, sprite)| (value, sprite.attributes, sprite.zero))
+        } else {
+            None
+        };
+
+        // an opaque sprite-0 pixel over an opaque background pixel, never at
+        // X 255; clipping counts as transparent
+        if let Some((_, _, true)) = sprite {
+            if bg_value != 0 && x != 255 {
+                self.status.set_sprite_zero_hit(true);
+            }
+        }
+
+        // Attribute bit 5 puts a sprite behind opaque background. Only the
+        // first opaque sprite is considered, so a behind-background sprite
+        // also hides any higher-numbered sprite under it: the hardware quirk
+        // games use to mask sprites with background.
+        match sprite {
+            Some((value, attributes, _)) if attributes & 0x20 == 0 || bg_value == 0 => {
+                self.palette_table[0x10 + (attributes as usize & 0b11) * 4 + value as usize]
+            }
+            _ if bg_value == 0 => self.palette_table[0],
+            _ => self.palette_table[bg_palette as usize * 4 + bg_value as usize],
+        }
+    }
+
+    /// Pattern tables and nametables as the rendering fetches see them.
+    fn read_vram(&self, addr: u16) -> u8 {
+        match addr {
+            0..=0x1fff => self.chr_rom.get(addr as usize).cloned().unwrap_or(0),
+            _ => self.vram[self.mirror_vram_addr(addr & 0x2fff) as usize],
+        }
+    }
+
+    /// PPUSTATUS as a read of $2002 would return it, without clearing vblank
+    /// or the address latch.
+    pub fn peek_status(&self) -> u8 {
+        self.status.bits()
+    }
+
+    /// What a read of $2007 would return, without refilling the read buffer
+    /// or advancing the VRAM address.
+    pub fn peek_data(&self) -> u8 {
+        let addr = self.loopy.addr();
+        match addr {
+            0..=0x3eff => self.internal_data_buf,
+            0x3f00..=0x3fff => {
+                // 32 entries mirrored up the page; $3F10/$14/$18/$1C are the
+                // entries below them
+                let index = (addr as usize - 0x3f00) & 0x1f;
+                let index = if index & 0x13 == 0x10 { index - 0x10 } else { index };
+                self.palette_table[index]
+            }
+            _ => self.internal_data_buf,
+        }
+    }
+
+    /// Dot within the current scanline, 0..=340.
+    pub fn cycle(&self) -> usize {
+        self.cycles
+    }
+
+    pub fn poll_nmi_interrupt(&mut self) -> Option<u8> {
+        self.nmi_interrupt.take()
     }
 
 }
@@ -124,6 +529,7 @@ impl PPU for NesPPU {
     fn write_to_ctrl(&mut self, value: u8) {
         let before_nmi_status = self.ctrl.generate_vblank_nmi();
         self.ctrl.update(value);
+        self.loopy.write_ctrl(value);
         if !before_nmi_status && self.ctrl.generate_vblank_nmi() && self.status.is_in_vblank() {
             self.nmi_interrupt = Some(1);
         }
@@ -133,6 +539,21 @@ impl PPU for NesPPU {
         self.mask.update(value);
     }
 
+    fn read_status(&mut self) -> u8 {
+        let data = self.status.snapshot();
+        self.status.reset_vblank_status();
+        self.loopy.reset_latch();
+        data
+    }
+
+    fn write_to_scroll(&mut self, value: u8) {
+        self.loopy.write_scroll(value);
+    }
+
+    fn write_to_ppu_addr(&mut self, value: u8) {
+        self.loopy.write_addr(value);
+    }
+
     fn write_to_oam_addr(&mut self, value: u8) {
         self.oam_addr = value;
     }
@@ -142,8 +563,12 @@ impl PPU for NesPPU {
         self.oam_addr = self.oam_addr.wrapping_add(1);
     }
 
+    fn read_oam_data(&self) -> u8 {
+        self.oam_data[self.oam_addr as usize]
+    }
+
     fn write_to_data(&mut self, value: u8) {
-        let addr = self.addr.get();
+        let addr = self.loopy.addr();
         match addr {
             0..=0x1fff => println!("attempt to write to chr rom space {}", addr),
             0x2000..=0x2fff => {
@@ -159,7 +584,7 @@ impl PPU for NesPPU {
     }
 
     fn read_data(&mut self) -> u8 {
-        let addr = self.addr.get();
+        let addr = self.loopy.addr();
 
         self.increment_vram_addr();
 
@@ -167,6 +592,7 @@ impl PPU for NesPPU {
             0..=0x1fff => {
                 let result = self.internal_data_buf;
                 self.internal_data_buf = self.chr_rom[addr as usize];
+                self.log_chr(addr as usize, 1, ChrFlags::READ);
                 result
             }
             0x2000..=0x2fff => {
@@ -192,3 +618,301 @@ impl PPU for NesPPU {
     }
 }
 
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const DOTS_PER_FRAME: usize = DOTS_PER_SCANLINE * SCANLINES_PER_FRAME as usize;
+
+    /// Tile 1 is solid colour 1, tile 2 solid colour 3; tile 0 is blank.
+    fn ppu() -> NesPPU {
+        let mut chr = vec![0; 0x2000];
+        for i in 0..8 {
+            chr[16 + i] = 0xff;
+            chr[32 + i] = 0xff;
+            chr[32 + 8 + i] = 0xff;
+        }
+        let mut ppu = NesPPU::new(chr, Mirroring::HORIZONTAL);
+        ppu.write_to_mask(0b0001_1110);
+        ppu.palette_table[0] = 0x0f;
+        ppu.palette_table[1] = 0x16;
+        ppu.palette_table[3] = 0x2a;
+        ppu.palette_table[0x13] = 0x21;
+        ppu
+    }
+
+    fn run_dots(ppu: &mut NesPPU, dots: usize) {
+        for _ in 0..dots {
+            ppu.tick(1);
+        }
+    }
+
+    fn pixel(ppu: &NesPPU, x: usize, y: usize) -> u16 {
+        ppu.pixels[y * SCREEN_WIDTH + x]
+    }
+
+    #[test]
+    fn test_background_tiles_and_attributes() {
+        let mut ppu = ppu();
+        // tile 1 down the left column, and palette 1 for the top-right
+        // quadrant of the first attribute block
+        for row in 0..30 {
+            ppu.vram[row * 32] = 1;
+        }
+        ppu.vram[2] = 1;
+        ppu.vram[0x3c0] = 0b0000_0100;
+        ppu.palette_table[5] = 0x30;
+
+        run_dots(&mut ppu, DOTS_PER_FRAME * 2);
+
+        assert_eq!(pixel(&ppu, 0, 0), 0x16);
+        assert_eq!(pixel(&ppu, 7, 239), 0x16);
+        assert_eq!(pixel(&ppu, 8, 0), 0x0f);
+        assert_eq!(pixel(&ppu, 16, 3), 0x30);
+    }
+
+    #[test]
+    fn test_scroll_split_takes_effect_next_line() {
+        let mut ppu = ppu();
+        for row in 0..30 {
+            ppu.vram[row * 32] = 1;
+        }
+        run_dots(&mut ppu, DOTS_PER_FRAME);
+
+        // a status bar split: scroll 4 pixels right from line 100 on
+        run_dots(&mut ppu, DOTS_PER_SCANLINE * 100 + 100);
+        ppu.write_to_scroll(4);
+        run_dots(&mut ppu, DOTS_PER_FRAME - DOTS_PER_SCANLINE * 100 - 100);
+
+        assert_eq!(pixel(&ppu, 4, 100), 0x16);
+        assert_eq!(pixel(&ppu, 4, 101), 0x0f);
+        assert_eq!(pixel(&ppu, 3, 101), 0x16);
+        assert_eq!(pixel(&ppu, 4, 239), 0x0f);
+    }
+
+    #[test]
+    fn test_mid_frame_ppu_addr_write() {
+        let mut ppu = ppu();
+        ppu.vram[5] = 1;
+        run_dots(&mut ppu, DOTS_PER_FRAME);
+
+        // after dot 257 of line 100, point v back at the top-left tile the
+        // way games reset scroll under a status bar
+        run_dots(&mut ppu, DOTS_PER_SCANLINE * 100 + 300);
+        ppu.read_status();
+        ppu.write_to_ppu_addr(0x00);
+        ppu.write_to_ppu_addr(0x00);
+        run_dots(&mut ppu, DOTS_PER_FRAME - DOTS_PER_SCANLINE * 100 - 300);
+
+        assert_eq!(pixel(&ppu, 40, 0), 0x16);
+        assert_eq!(pixel(&ppu, 40, 100), 0x0f);
+        assert_eq!(pixel(&ppu, 40, 101), 0x16);
+        assert_eq!(pixel(&ppu, 47, 108), 0x16);
+        assert_eq!(pixel(&ppu, 40, 109), 0x0f);
+    }
+
+    #[test]
+    fn test_rendering_disabled_leaves_v_alone() {
+        let mut ppu = ppu();
+        ppu.write_to_mask(0);
+        ppu.write_to_ppu_addr(0x21);
+        ppu.write_to_ppu_addr(0x08);
+        run_dots(&mut ppu, DOTS_PER_FRAME);
+        assert_eq!(ppu.loopy.addr(), 0x2108);
+    }
+
+    #[test]
+    fn test_sprites_drawn_per_line() {
+        let mut ppu = ppu();
+        // sprite at Y=20 first appears on line 21, flipped tile 2 at X=100
+        ppu.oam_data[0..4].copy_from_slice(&[20, 2, 0b1100_0000, 100]);
+        run_dots(&mut ppu, DOTS_PER_FRAME * 2);
+
+        assert_eq!(pixel(&ppu, 100, 20), 0x0f);
+        assert_eq!(pixel(&ppu, 100, 21), 0x21);
+        assert_eq!(pixel(&ppu, 107, 28), 0x21);
+        assert_eq!(pixel(&ppu, 108, 28), 0x0f);
+        assert_eq!(pixel(&ppu, 100, 29), 0x0f);
+    }
+
+    fn status_after_line(ppu: &mut NesPPU, line: usize) -> u8 {
+        run_dots(ppu, DOTS_PER_SCANLINE * (line + 1));
+        ppu.peek_status()
+    }
+
+    #[test]
+    fn test_eight_sprites_per_line() {
+        let mut ppu = ppu();
+        for i in 0..9 {
+            ppu.oam_data[i * 4..i * 4 + 4].copy_from_slice(&[20, 2, 0, i as u8 * 10]);
+        }
+        run_dots(&mut ppu, DOTS_PER_FRAME);
+        assert_eq!(status_after_line(&mut ppu, 21) & 0x20, 0x20);
+        run_dots(&mut ppu, DOTS_PER_FRAME - DOTS_PER_SCANLINE * 22);
+
+        assert_eq!(pixel(&ppu, 70, 21), 0x21);
+        assert_eq!(pixel(&ppu, 80, 21), 0x0f);
+
+        ppu.unlimited_sprites = true;
+        run_dots(&mut ppu, DOTS_PER_FRAME);
+        assert_eq!(pixel(&ppu, 80, 21), 0x21);
+    }
+
+    #[test]
+    fn test_overflow_bug_reads_wrong_bytes() {
+        let mut fake = ppu();
+        for i in 0..8 {
+            fake.oam_data[i * 4..i * 4 + 4].copy_from_slice(&[20, 2, 0, i as u8 * 10]);
+        }
+        // sprite 8 is off the line; the buggy check then reads sprite 9's
+        // tile number as its Y, and 20 is in range
+        fake.oam_data[32..36].copy_from_slice(&[200, 0, 0, 0]);
+        fake.oam_data[36..40].copy_from_slice(&[200, 20, 0, 0]);
+        for i in 10..64 {
+            fake.oam_data[i * 4] = 200;
+        }
+        run_dots(&mut fake, DOTS_PER_FRAME);
+        assert_eq!(status_after_line(&mut fake, 21) & 0x20, 0x20);
+
+        // and a ninth sprite can be missed when the index has moved off Y
+        let mut missed = ppu();
+        for i in 0..8 {
+            missed.oam_data[i * 4..i * 4 + 4].copy_from_slice(&[20, 2, 0, i as u8 * 10]);
+        }
+        missed.oam_data[32..36].copy_from_slice(&[200, 0, 0, 0]);
+        missed.oam_data[36..40].copy_from_slice(&[20, 0, 0, 0]);
+        for i in 10..64 {
+            missed.oam_data[i * 4..i * 4 + 4].copy_from_slice(&[200, 200, 200, 200]);
+        }
+        run_dots(&mut missed, DOTS_PER_FRAME);
+        assert_eq!(status_after_line(&mut missed, 30) & 0x20, 0);
+    }
+
+    #[test]
+    fn test_sprite_zero_hit_needs_opaque_pixels() {
+        let mut miss = ppu();
+        miss.vram[3 * 32 + 12] = 1;
+        // over blank background: no hit
+        miss.oam_data[0..4].copy_from_slice(&[50, 2, 0, 96]);
+        // sprite 1 overlaps the tile but isn't sprite 0
+        miss.oam_data[4..8].copy_from_slice(&[23, 2, 0, 96]);
+        run_dots(&mut miss, DOTS_PER_FRAME);
+        assert_eq!(status_after_line(&mut miss, 60) & 0x40, 0);
+
+        let mut hit = ppu();
+        hit.vram[3 * 32 + 12] = 1;
+        hit.oam_data[0..4].copy_from_slice(&[23, 2, 0, 100]);
+        run_dots(&mut hit, DOTS_PER_FRAME);
+        assert_eq!(status_after_line(&mut hit, 23) & 0x40, 0);
+        run_dots(&mut hit, DOTS_PER_SCANLINE);
+        assert_eq!(hit.peek_status() & 0x40, 0x40);
+    }
+
+    #[test]
+    fn test_status_flags_clear_on_pre_render_line() {
+        let mut ppu = ppu();
+        ppu.vram[3 * 32 + 12] = 1;
+        ppu.oam_data[0..4].copy_from_slice(&[23, 2, 0, 100]);
+        run_dots(&mut ppu, DOTS_PER_SCANLINE * VBLANK_SCANLINE as usize + 1);
+        assert_eq!(ppu.peek_status() & 0xc0, 0xc0);
+
+        // vblank and the hit both last until dot 1 of the pre-render line
+        run_dots(&mut ppu, DOTS_PER_SCANLINE * (PRE_RENDER_SCANLINE - VBLANK_SCANLINE) as usize);
+        assert_eq!(ppu.peek_status() & 0xc0, 0xc0);
+        run_dots(&mut ppu, 1);
+        assert_eq!(ppu.peek_status() & 0xc0, 0);
+    }
+
+    #[test]
+    fn test_8x16_sprites() {
+        let mut ppu = ppu();
+        // tiles 2 and 3 of the right pattern table, picked by the odd tile number
+        for i in 0..8 {
+            ppu.chr_rom[0x1000 + 32 + i] = 0xff;
+            ppu.chr_rom[0x1000 + 48 + i] = 0xff;
+            ppu.chr_rom[0x1000 + 48 + 8 + i] = 0xff;
+        }
+        ppu.palette_table[0x11] = 0x12;
+        ppu.write_to_ctrl(0b0010_0000);
+        ppu.oam_data[0..4].copy_from_slice(&[50, 3, 0, 40]);
+        ppu.oam_data[4..8].copy_from_slice(&[50, 3, 0x80, 80]);
+        run_dots(&mut ppu, DOTS_PER_FRAME * 2);
+
+        assert_eq!(pixel(&ppu, 40, 51), 0x12);
+        assert_eq!(pixel(&ppu, 40, 59), 0x21);
+        assert_eq!(pixel(&ppu, 40, 66), 0x21);
+        assert_eq!(pixel(&ppu, 40, 67), 0x0f);
+        // flipped: the odd tile is on top
+        assert_eq!(pixel(&ppu, 80, 51), 0x21);
+        assert_eq!(pixel(&ppu, 80, 66), 0x12);
+    }
+
+    #[test]
+    fn test_sprite_priority() {
+        let mut ppu = ppu();
+        ppu.vram[3 * 32 + 12] = 1;
+        ppu.vram[3 * 32 + 14] = 1;
+        // behind the background: hidden over the tile, shown past its edge
+        ppu.oam_data[0..4].copy_from_slice(&[23, 2, 0x20, 100]);
+        // a front sprite under a behind one is hidden as well
+        ppu.oam_data[4..8].copy_from_slice(&[23, 2, 0x20, 112]);
+        ppu.oam_data[8..12].copy_from_slice(&[23, 2, 0x00, 112]);
+        run_dots(&mut ppu, DOTS_PER_FRAME * 2);
+
+        assert_eq!(pixel(&ppu, 100, 24), 0x16);
+        assert_eq!(pixel(&ppu, 104, 24), 0x21);
+        assert_eq!(pixel(&ppu, 112, 24), 0x16);
+        assert_eq!(pixel(&ppu, 119, 24), 0x16);
+    }
+
+    #[test]
+    fn test_left_column_clipping() {
+        let mut ppu = ppu();
+        ppu.vram[0] = 1;
+        ppu.oam_data[0..4].copy_from_slice(&[0, 2, 0, 4]);
+        ppu.oam_data[4..8].copy_from_slice(&[10, 2, 0, 0]);
+        // show both layers, but not in the leftmost 8 pixels
+        ppu.write_to_mask(0b0001_1000);
+        run_dots(&mut ppu, DOTS_PER_FRAME * 2);
+
+        assert_eq!(pixel(&ppu, 7, 1), 0x0f);
+        assert_eq!(pixel(&ppu, 7, 12), 0x0f);
+        assert_eq!(pixel(&ppu, 8, 1), 0x21);
+        // sprite 0 only meets the background inside the clipped column
+        assert_eq!(ppu.peek_status() & 0x40, 0);
+    }
+
+    #[test]
+    fn test_layers_switched_off() {
+        let mut ppu = ppu();
+        ppu.vram[0] = 1;
+        ppu.oam_data[0..4].copy_from_slice(&[10, 2, 0, 0]);
+        ppu.write_to_mask(0b0001_0110);
+        run_dots(&mut ppu, DOTS_PER_FRAME * 2);
+        assert_eq!(pixel(&ppu, 0, 0), 0x0f);
+        assert_eq!(pixel(&ppu, 0, 11), 0x21);
+
+        ppu.write_to_mask(0b0000_1110);
+        run_dots(&mut ppu, DOTS_PER_FRAME);
+        assert_eq!(pixel(&ppu, 0, 0), 0x16);
+        assert_eq!(pixel(&ppu, 0, 11), 0x0f);
+
+        // fully off with v in palette RAM shows that entry
+        ppu.write_to_mask(0);
+        ppu.write_to_ppu_addr(0x3f);
+        ppu.write_to_ppu_addr(0x01);
+        run_dots(&mut ppu, DOTS_PER_FRAME);
+        assert_eq!(pixel(&ppu, 200, 200), 0x16);
+    }
+
+    #[test]
+    fn test_greyscale_and_emphasis() {
+        let mut ppu = ppu();
+        ppu.vram[0] = 1;
+        ppu.write_to_mask(0b1010_1111);
+        run_dots(&mut ppu, DOTS_PER_FRAME * 2);
+        assert_eq!(pixel(&ppu, 0, 0), 0b101 << 6 | 0x10);
+        assert_eq!(pixel(&ppu, 8, 0), 0b101 << 6 | 0x00);
+    }
+}
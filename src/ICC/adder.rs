@@ -1,43 +0,0 @@
-pub struct AdderRegister {
-    value: (u8, u8),
-    hi_ptr: bool,
-}
-
-impl AdderRegister {
-    pub fn new() -> Self {
-        AdderRegister {
-            value: (0, 0),
-            hi_ptr: true,
-        }
-    }
-
-    fn set(&mut self, data: u16) {
-        self.value.0 = (data >> 8) as u8;
-        self.value.1 = (data & 0xff) as u8;
-    }
-
-    pub fn update(&mut self, data: u8) {
-        if self.hi_ptr {
-            self.value.0 = data;
-        } else {
-            self.value.1 = data;
-        }
-
-        if self.get() > 0x3fff {
-            self.set(self.get() & 0b11111111111111); 
-        }
-
-        self.hi_ptr = !self.hi_ptr;
-    }
-
-    pub fn increment(&mut self, inc: u8) {
-        let lo = self.value.1;
-        if lo > self.value.1.wrapping_add(inc){
-            self.value.0 = self.value.0.wrapping_add(1);
-        }
-        if self.get() > 0x3fff {
-            self.set(self.get() & 0b11111111111111); 
-        }
-    }
-
-}
@@ -9,7 +9,7 @@ const PPU_REGISTERS_MIRRORS_END: u16 = 0x3FFF;
 
 
 impl Mem for Bus {
-    fn mem_read(&self, addr: u16) -> u8 {
+    fn mem_read(&mut self, addr: u16) -> u8 {
         match addr {
             RAM ..= RAM_MIRRORS_END => {
                 let mirror_down_addr = addr & 0b00000111_11111111;
@@ -25,6 +25,16 @@ impl Mem for Bus {
         }
     }
 
+    fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            RAM ..= RAM_MIRRORS_END => {
+                let mirror_down_addr = addr & 0b00000111_11111111;
+                self.cpu_vram[mirror_down_addr as usize]
+            }
+            _ => 0,
+        }
+    }
+
     fn mem_write(&mut self, addr: u16, data: u8) {
         match addr {
             RAM ..= RAM_MIRRORS_END => {
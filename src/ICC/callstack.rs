@@ -0,0 +1,199 @@
+use std::collections::VecDeque;
+use std::fmt;
+
+/// The hardware stack is 256 bytes, so anything deeper than this has long
+/// since overwritten its own return addresses.
+const MAX_FRAMES: usize = 128;
+const MAX_MISMATCHES: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameKind {
+    Jsr,
+    Nmi,
+    Irq,
+    Brk,
+}
+
+impl FrameKind {
+    fn is_interrupt(self) -> bool {
+        self != FrameKind::Jsr
+    }
+}
+
+impl fmt::Display for FrameKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            FrameKind::Jsr => "JSR",
+            FrameKind::Nmi => "NMI",
+            FrameKind::Irq => "IRQ",
+            FrameKind::Brk => "BRK",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    pub kind: FrameKind,
+    /// The JSR or BRK, or the instruction an NMI/IRQ interrupted.
+    pub call_site: u16,
+    /// Subroutine or interrupt handler entered.
+    pub target: u16,
+    /// Where the matching RTS or RTI should land.
+    pub return_addr: u16,
+}
+
+/// An RTS or RTI that didn't go back where the shadow stack expected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mismatch {
+    /// Address of the RTS/RTI.
+    pub pc: u16,
+    pub returned_to: u16,
+    /// The frame on top when it ran, if there was one.
+    pub expected: Option<Frame>,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "return at ${:04X} went to ${:04X}", self.pc, self.returned_to)?;
+        match self.expected {
+            Some(frame) => write!(
+                f,
+                ", expected ${:04X} for {} at ${:04X}",
+                frame.return_addr, frame.kind, frame.call_site
+            ),
+            None => write!(f, " with no call on the shadow stack"),
+        }
+    }
+}
+
+/// Shadow of the hardware stack that only tracks calls and interrupts, so a
+/// backtrace survives whatever the program does with PHA/PLA. Returns are
+/// matched against it; one that doesn't fit is recorded as a `Mismatch`,
+/// since it usually means an RTS jump table, a manual stack unwind or
+/// corruption.
+pub struct CallStack {
+    frames: Vec<Frame>,
+    mismatches: VecDeque<Mismatch>,
+}
+
+impl CallStack {
+    pub fn new() -> Self {
+        CallStack {
+            frames: vec![],
+            mismatches: VecDeque::new(),
+        }
+    }
+
+    /// Innermost frame last.
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    /// Most recent mismatches, oldest first.
+    pub fn mismatches(&self) -> impl Iterator<Item = &Mismatch> {
+        self.mismatches.iter()
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+        self.mismatches.clear();
+    }
+
+    pub fn call(&mut self, frame: Frame) {
+        if self.frames.len() == MAX_FRAMES {
+            self.frames.remove(0);
+        }
+        self.frames.push(frame);
+    }
+
+    fn mismatch(&mut self, mismatch: Mismatch) {
+        if self.mismatches.len() == MAX_MISMATCHES {
+            self.mismatches.pop_front();
+        }
+        self.mismatches.push_back(mismatch);
+    }
+
+    /// Records an RTS (`interrupt` false) or RTI at `pc` that went to
+    /// `returned_to`. A return to an outer frame unwinds everything above it,
+    /// which is flagged; a return that matches no frame leaves the stack
+    /// alone, since RTS is also used as an indirect jump.
+    pub fn ret(&mut self, pc: u16, returned_to: u16, interrupt: bool) {
+        let matching = self
+            .frames
+            .iter()
+            .rposition(|f| f.return_addr == returned_to && f.kind.is_interrupt() == interrupt);
+        let top = self.frames.last().cloned();
+
+        match matching {
+            Some(index) if index + 1 == self.frames.len() => {
+                self.frames.pop();
+            }
+            Some(index) => {
+                self.frames.truncate(index);
+                self.mismatch(Mismatch { pc: pc, returned_to: returned_to, expected: top });
+            }
+            None => self.mismatch(Mismatch { pc: pc, returned_to: returned_to, expected: top }),
+        }
+    }
+
+    /// Innermost frame first, starting at `pc`. `label` names addresses when
+    /// symbols are loaded; anything it doesn't know prints as hex.
+    pub fn backtrace<F>(&self, pc: u16, label: F) -> String
+    where
+        F: Fn(u16) -> Option<String>,
+    {
+        let name = |addr: u16| label(addr).unwrap_or_else(|| format!("${:04X}", addr));
+        let mut lines = vec![];
+
+        let mut at = pc;
+        for (depth, frame) in self.frames.iter().rev().enumerate() {
+            lines.push(format!("#{:<2} ${:04X} in {}", depth, at, name(frame.target)));
+            if frame.kind.is_interrupt() {
+                lines.push(format!("    <{} from ${:04X}>", frame.kind, frame.call_site));
+            }
+            at = frame.call_site;
+        }
+        lines.push(format!("#{:<2} ${:04X}", self.frames.len(), at));
+
+        for mismatch in self.mismatches.iter() {
+            lines.push(format!("!! {}", mismatch));
+        }
+        lines.join("\n")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn jsr(call_site: u16, target: u16) -> Frame {
+        Frame {
+            kind: FrameKind::Jsr,
+            call_site: call_site,
+            target: target,
+            return_addr: call_site + 3,
+        }
+    }
+
+    #[test]
+    fn test_unwind_and_mismatches() {
+        let mut stack = CallStack::new();
+        stack.call(jsr(0x8000, 0x9000));
+        stack.call(jsr(0x9010, 0xa000));
+
+        // RTS used as a jump: nothing was called from $C000
+        stack.ret(0xa005, 0xc000, false);
+        assert_eq!(stack.frames().len(), 2);
+
+        // skips the inner frame straight back to the outer caller
+        stack.ret(0xa010, 0x8003, false);
+        assert!(stack.frames().is_empty());
+        assert_eq!(stack.mismatches().count(), 2);
+
+        stack.call(jsr(0x8000, 0x9000));
+        stack.ret(0x9001, 0x8003, false);
+        assert!(stack.frames().is_empty());
+        assert_eq!(stack.mismatches().count(), 2);
+    }
+}
@@ -0,0 +1,188 @@
+/// The PPU's internal scroll registers, named after loopy's write-up.
+/// $2005 and $2006 share one write toggle and both build up `t`; the
+/// background fetches run off `v`, which is also the $2007 address.
+///
+/// `v` and `t` are laid out as `yyy NN YYYYY XXXXX`: fine Y, nametable,
+/// coarse Y, coarse X.
+pub struct LoopyRegister {
+    /// Current VRAM address.
+    pub v: u16,
+    /// Temporary address, copied into `v` during rendering.
+    pub t: u16,
+    /// Fine X scroll.
+    pub x: u8,
+    /// First/second write toggle.
+    pub w: bool,
+}
+
+const COARSE_X: u16 = 0x001f;
+const COARSE_Y: u16 = 0x03e0;
+const NAMETABLE_X: u16 = 0x0400;
+const NAMETABLE_Y: u16 = 0x0800;
+const FINE_Y: u16 = 0x7000;
+
+impl LoopyRegister {
+    pub fn new() -> Self {
+        LoopyRegister {
+            v: 0,
+            t: 0,
+            x: 0,
+            w: false,
+        }
+    }
+
+    /// The 14-bit address the PPU bus sees.
+    pub fn addr(&self) -> u16 {
+        self.v & 0x3fff
+    }
+
+    /// $2000: nametable select goes into `t`.
+    pub fn write_ctrl(&mut self, value: u8) {
+        self.t = (self.t & !(NAMETABLE_X | NAMETABLE_Y)) | ((value as u16 & 0b11) << 10);
+    }
+
+    /// $2002 read.
+    pub fn reset_latch(&mut self) {
+        self.w = false;
+    }
+
+    /// $2005: X then Y.
+    pub fn write_scroll(&mut self, value: u8) {
+        if !self.w {
+            self.t = (self.t & !COARSE_X) | (value as u16 >> 3);
+            self.x = value & 0b111;
+        } else {
+            self.t = (self.t & !(FINE_Y | COARSE_Y)) | ((value as u16 & 0b111) << 12) | ((value as u16 & 0xf8) << 2);
+        }
+        self.w = !self.w;
+    }
+
+    /// $2006: high byte then low byte. The second write lands in `v` at once,
+    /// which is how games change scroll mid-frame.
+    pub fn write_addr(&mut self, value: u8) {
+        if !self.w {
+            self.t = (self.t & 0x00ff) | ((value as u16 & 0x3f) << 8);
+        } else {
+            self.t = (self.t & 0xff00) | value as u16;
+            self.v = self.t;
+        }
+        self.w = !self.w;
+    }
+
+    /// After a $2007 access outside rendering.
+    pub fn increment(&mut self, step: u8) {
+        self.v = self.v.wrapping_add(step as u16) & 0x7fff;
+    }
+
+    pub fn fine_y(&self) -> u16 {
+        (self.v & FINE_Y) >> 12
+    }
+
+    /// Every eighth dot, moving to the next tile and into the neighbouring
+    /// nametable after column 31.
+    pub fn increment_x(&mut self) {
+        if self.v & COARSE_X == 31 {
+            self.v &= !COARSE_X;
+            self.v ^= NAMETABLE_X;
+        } else {
+            self.v += 1;
+        }
+    }
+
+    /// Dot 256: next pixel row. Row 29 wraps into the next nametable; rows 30
+    /// and 31 (attribute memory) wrap to 0 in the same one.
+    pub fn increment_y(&mut self) {
+        if self.v & FINE_Y != FINE_Y {
+            self.v += 0x1000;
+            return;
+        }
+        self.v &= !FINE_Y;
+        let mut coarse_y = (self.v & COARSE_Y) >> 5;
+        if coarse_y == 29 {
+            coarse_y = 0;
+            self.v ^= NAMETABLE_Y;
+        } else if coarse_y == 31 {
+            coarse_y = 0;
+        } else {
+            coarse_y += 1;
+        }
+        self.v = (self.v & !COARSE_Y) | (coarse_y << 5);
+    }
+
+    /// Dot 257: horizontal position from `t`.
+    pub fn copy_x(&mut self) {
+        let mask = NAMETABLE_X | COARSE_X;
+        self.v = (self.v & !mask) | (self.t & mask);
+    }
+
+    /// Dots 280-304 of the pre-render line: vertical position from `t`.
+    pub fn copy_y(&mut self) {
+        let mask = FINE_Y | NAMETABLE_Y | COARSE_Y;
+        self.v = (self.v & !mask) | (self.t & mask);
+    }
+
+    /// Nametable byte for the tile at `v`.
+    pub fn tile_addr(&self) -> u16 {
+        0x2000 | (self.v & 0x0fff)
+    }
+
+    /// Attribute byte covering the tile at `v`.
+    pub fn attribute_addr(&self) -> u16 {
+        0x23c0 | (self.v & (NAMETABLE_X | NAMETABLE_Y)) | ((self.v >> 4) & 0x38) | ((self.v >> 2) & 0x07)
+    }
+
+    /// Shift that brings the tile's 2-bit palette down from the attribute byte.
+    pub fn attribute_shift(&self) -> u16 {
+        ((self.v >> 4) & 0b100) | (self.v & 0b10)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_shared_write_toggle() {
+        let mut loopy = LoopyRegister::new();
+        loopy.write_ctrl(0b10);
+        loopy.write_scroll(0x7d);
+        assert_eq!((loopy.t, loopy.x, loopy.w), (0x080f, 0b101, true));
+        loopy.write_scroll(0x5e);
+        assert_eq!((loopy.t, loopy.w), (0x696f, false));
+
+        // $2006 shares the toggle with $2005, and its second write loads v
+        loopy.write_scroll(0x00);
+        loopy.reset_latch();
+        loopy.write_addr(0x3d);
+        assert_eq!((loopy.t, loopy.v), (0x3d60, 0));
+        loopy.write_addr(0xf0);
+        assert_eq!((loopy.t, loopy.v, loopy.w), (0x3df0, 0x3df0, false));
+    }
+
+    #[test]
+    fn test_increments_and_copies() {
+        let mut loopy = LoopyRegister::new();
+        loopy.v = 31;
+        loopy.increment_x();
+        assert_eq!(loopy.v, NAMETABLE_X);
+
+        loopy.v = FINE_Y | (29 << 5);
+        loopy.increment_y();
+        assert_eq!(loopy.v, NAMETABLE_Y);
+        loopy.v = FINE_Y | (31 << 5) | NAMETABLE_Y;
+        loopy.increment_y();
+        assert_eq!(loopy.v, NAMETABLE_Y);
+
+        loopy.t = 0x7fff;
+        loopy.v = 0;
+        loopy.copy_x();
+        assert_eq!(loopy.v, 0x041f);
+        loopy.copy_y();
+        assert_eq!(loopy.v, 0x7fff);
+
+        loopy.v = 0x0c00 | (17 << 5) | 22;
+        assert_eq!(loopy.tile_addr(), 0x2e36);
+        assert_eq!(loopy.attribute_addr(), 0x2fe5);
+        assert_eq!(loopy.attribute_shift(), 0b010);
+    }
+}
@@ -1,9 +1,21 @@
 pub mod bus;
 pub mod cartridge;
 pub mod cpu;
+pub mod callstack;
 pub mod opcodes;
 pub mod trace;
+pub mod disasm;
+pub mod assembler;
+pub mod nestest;
+pub mod dormann;
+pub mod expr;
+pub mod debugger;
+pub mod gdbstub;
+pub mod symbols;
+pub mod cdl;
+pub mod profiler;
 pub mod ppu;
+pub mod loopy;
 
 use bus::Bus;
 use cartridge::Rom;
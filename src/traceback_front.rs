@@ -1,113 +0,0 @@
-use crate::cpu::Mem;
-use crate::cpu::CPU;
-use crate::opcodes;
-use std::collections::HashMap;
-
-lazy_static! {
-    pub static ref NON_READABLE_ADDR: Vec<u16> =
-        vec!(0x2001, 0x2002, 0x2003, 0x2004, 0x2005, 0x2006, 0x2007, 0x4016, 0x4017);
-}
-
-pub fn trace(cpu: &mut CPU) -> String {
-    let ref opscodes: HashMap<u8, &'static opcodes::OpCode> = *opcodes::OPCODES_MAP;
-
-
-    let code = cpu.mem_read(cpu.program_counter);
-    let ops = opscodes.get(&code).unwrap();
-
-    let begin = cpu.program_counter;
-    let mut hex_dump = vec![];
-
-    let (mem_addr, stored_value) = match ops.mode {
-        AddressingMode::Immediate | AddressingMode::NoneAddressing => (0, 0),
-        _ => {
-            let (addr, _) = cpu.get_absolute_address(&ops.mode, begin + 1);
-
-            if !non_readable_addr.contains(&addr) {
-                (addr, cpu.mem_read(addr))
-            }
-        }
-    };
-
-    let tmp = match ops.len {
-        1 => match ops.code {
-            0x0a | 0x4a | 0x2a | 0x6a => format!("A "),
-            _ => String::from(""),
-        },
-        2 => {
-            let address: u8 = cpu.mem_read(begin + 1);
-            hex_dump.push(address);
-
-            match ops.mode {
-                AddressingMode::Immediate => format!("#${:02x}", address),
-                AddressingMode::ZeroPage_X => format!(
-                    "${:02x},X @ {:02x} = {:02x}",
-                    address, mem_addr, stored_value
-                ),
-                AddressingMode::ZeroPage_Y => format!(
-                    "${:02x},Y @ {:02x} = {:02x}",
-                    address, mem_addr, stored_value
-                ),
-                AddressingMode::Indirect_X => format!(
-                    "(${:02x},X) @ {:02x} = {:04x} = {:02x}",
-                    address,
-                    (address.wrapping_add(cpu.register_x)),
-                    mem_addr,
-                    stored_value
-                ),
-                AddressingMode::Indirect_Y => format!(
-                    "(${:02x}),Y = {:04x} @ {:04x} = {:02x}",
-                    address,
-                    (mem_addr.wrapping_sub(cpu.register_y as u16)),
-                    mem_addr,
-                    stored_value
-                ),
-                AddressingMode::NoneAddressing => {
-                    let address: usize =
-                        (begin as usize + 2).wrapping_add((address as i8) as usize);
-                    format!("${:04x}", address)
-                }
-            }
-        }
-        3 => {
-            let address_lo = cpu.mem_read(begin + 1);
-            let address_hi = cpu.mem_read(begin + 2);
-            let address = cpu.mem_read_u16(begin + 1);
-            match ops.mode {
-                AddressingMode::NoneAddressing => {
-                    if ops.code == 0x6c {
-                        let jmp_addr = if address & 0x00FF == 0x00FF {
-                            let lo = cpu.mem_read(address);
-                            let hi = cpu.mem_read(address & 0xFF00);
-                            (hi as u16) << 8 | (lo as u16)
-                        } else {
-                            cpu.mem_read_u16(address)
-                        };
-                        format!("(${:04x}) = {:04x}", address, jmp_addr)
-                    } else {
-                        format!("${:04x}", address)
-                    }
-                }
-                AddressingMode::Absolute => format!("${:04x} = {:02x}", mem_addr, stored_value),
-                AddressingMode::Absolute_X => format!(
-                    "${:04x},X @ {:04x} = {:02x}",
-                    address, mem_addr, stored_value
-                ),
-                AddressingMode::Absolute_Y => format!(
-                    "${:04x},Y @ {:04x} = {:02x}",
-                    address, mem_addr, stored_value
-                )
-            }
-        }
-    };
-
-    let hex_str = hex_dump
-        .iter()
-        .map(|z| format!("{:02x}", z))
-        .collect::<Vec<String>>()
-        .join(" ");
-    let asm_str = format!("{:04x}  {:8} {: >4} {}", begin, hex_str, ops.mnemonic, tmp)
-        .trim()
-        .to_string();
-}
-